@@ -1,4 +1,4 @@
-use tokio_stream_multiplexor::{Config, DuplexStream, WebSocketMultiplexor};
+use websocket_multiplexor::{Config, MuxStream, WebSocketMultiplexor};
 
 use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 use std::sync::Arc;
@@ -30,21 +30,21 @@ async fn get_tcp_stream_pair() -> (TcpStream, TcpStream) {
 }
 
 async fn get_mux_stream_pair() -> (
-    WebSocketMultiplexor<TcpStream>,
-    WebSocketMultiplexor<TcpStream>,
-    DuplexStream,
-    DuplexStream,
+    WebSocketMultiplexor,
+    WebSocketMultiplexor,
+    MuxStream,
+    MuxStream,
 ) {
     let (stream0, stream1) = get_tcp_stream_pair().await;
 
-    let mux0 = WebSocketMultiplexor::new(stream0, Config::default());
-    let mux1 = WebSocketMultiplexor::new(stream1, Config::default());
+    let mux0 = WebSocketMultiplexor::from_io(stream0, Config::default());
+    let mux1 = WebSocketMultiplexor::from_io(stream1, Config::default());
 
     let (tx, mut rx) = mpsc::channel(1);
     let mux1 = Arc::from(mux1);
     let listener1 = mux1.bind(22).await.unwrap();
     tokio::spawn(async move {
-        let stream = listener1.accept().await.unwrap();
+        let (stream, _) = listener1.accept().await.unwrap();
         tx.send(stream).await.unwrap();
     });
 
@@ -58,15 +58,13 @@ async fn tcp_throughput() {
     let (mut stream0, mut stream1) = get_tcp_stream_pair().await;
 
     tokio::spawn(async move {
-        let mut buf: Vec<u8> = vec![];
-        buf.resize(PAYLOAD_SIZE, 0);
+        let buf = vec![0u8; PAYLOAD_SIZE];
         for _ in 0..SEND_ROUND {
             stream0.write_all(&buf).await.unwrap();
         }
     });
 
-    let mut buf: Vec<u8> = vec![];
-    buf.resize(PAYLOAD_SIZE, 0);
+    let mut buf = vec![0u8; PAYLOAD_SIZE];
     for _ in 0..SEND_ROUND {
         stream1.read_exact(&mut buf).await.unwrap();
     }
@@ -76,15 +74,13 @@ async fn mux_throughput() {
     let (_mux0, _mux1, mut stream0, mut stream1) = get_mux_stream_pair().await;
 
     tokio::spawn(async move {
-        let mut buf: Vec<u8> = vec![];
-        buf.resize(PAYLOAD_SIZE, 0);
+        let buf = vec![0u8; PAYLOAD_SIZE];
         for _ in 0..SEND_ROUND {
             stream0.write_all(&buf).await.unwrap();
         }
     });
 
-    let mut buf: Vec<u8> = vec![];
-    buf.resize(PAYLOAD_SIZE, 0);
+    let mut buf = vec![0u8; PAYLOAD_SIZE];
     for _ in 0..SEND_ROUND {
         stream1.read_exact(&mut buf).await.unwrap();
     }
@@ -92,8 +88,8 @@ async fn mux_throughput() {
 
 async fn mux_handshake() {
     let (stream0, stream1) = get_tcp_stream_pair().await;
-    let mux0 = WebSocketMultiplexor::new(stream0, Config::default());
-    let mux1 = WebSocketMultiplexor::new(stream1, Config::default());
+    let mux0 = WebSocketMultiplexor::from_io(stream0, Config::default());
+    let mux1 = WebSocketMultiplexor::from_io(stream1, Config::default());
 
     for i in 0..HANDSHAKE_ROUND {
         let listener = mux0.bind(i as u16 + 1).await.unwrap();
@@ -111,12 +107,10 @@ pub fn throughput(c: &mut Criterion) {
     let mut group = c.benchmark_group("throughput");
     group.throughput(Throughput::Bytes((PAYLOAD_SIZE * SEND_ROUND) as u64));
     group.bench_function("tcp", |b| {
-        b.to_async(Runtime::new().unwrap())
-            .iter(|| tcp_throughput())
+        b.to_async(Runtime::new().unwrap()).iter(tcp_throughput)
     });
     group.bench_function("mux", |b| {
-        b.to_async(Runtime::new().unwrap())
-            .iter(|| mux_throughput())
+        b.to_async(Runtime::new().unwrap()).iter(mux_throughput)
     });
     group.finish();
 }
@@ -125,7 +119,7 @@ pub fn handshake(c: &mut Criterion) {
     let mut group = c.benchmark_group("handshake");
     group.throughput(Throughput::Elements(HANDSHAKE_ROUND as u64));
     group.bench_function("mux", |b| {
-        b.to_async(Runtime::new().unwrap()).iter(|| mux_handshake())
+        b.to_async(Runtime::new().unwrap()).iter(mux_handshake)
     });
     group.finish();
 }
@@ -18,7 +18,7 @@ async fn main() -> Result<(), std::io::Error> {
 
     let listener = mux_0.bind(23).await?;
     tokio::spawn(async move {
-        while let Ok(mut stream) = listener.accept().await {
+        while let Ok((mut stream, _)) = listener.accept().await {
             let _ = stream.write_all(b"Hello, world!").await;
         }
     });
@@ -0,0 +1,98 @@
+use std::io::{self, Read};
+
+use crate::hello::Features;
+
+/// Codec compressing the data a stream sends, see
+/// `ConnectOptions::compression` and `Config::compression`.
+///
+/// Codecs other than `None` are enabled by the cargo feature of the same
+/// name, and only used if the peer supports them too.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// Send data as is.
+    #[default]
+    None,
+    /// Deflate, as used by zlib and gzip.
+    #[cfg(feature = "deflate")]
+    Deflate,
+    /// Zstandard.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// Identifies the codec in the `Compression` Syn option and in
+    /// compressed data frames.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Self::None => 0,
+            #[cfg(feature = "deflate")]
+            Self::Deflate => 1,
+            #[cfg(feature = "zstd")]
+            Self::Zstd => 2,
+        }
+    }
+
+    /// The codec identified by `id`, if this build supports it.
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::None),
+            #[cfg(feature = "deflate")]
+            1 => Some(Self::Deflate),
+            #[cfg(feature = "zstd")]
+            2 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// The feature both peers need to use this codec.
+    pub(crate) fn feature(self) -> Features {
+        match self {
+            Self::None => Features::default(),
+            #[cfg(feature = "deflate")]
+            Self::Deflate => Features::DEFLATE,
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Features::ZSTD,
+        }
+    }
+
+    /// Compress `data`, or `None` if that does not make it smaller.
+    pub(crate) fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        let compressed: Vec<u8> = match self {
+            Self::None => None,
+            #[cfg(feature = "deflate")]
+            Self::Deflate => {
+                use std::io::Write;
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(data).ok()?;
+                encoder.finish().ok()
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::bulk::compress(data, 0).ok(),
+        }?;
+        (compressed.len() < data.len()).then_some(compressed)
+    }
+
+    /// Decompress `data`, failing with `InvalidData` if it is corrupt or
+    /// decompresses to more than `limit` bytes.
+    pub(crate) fn decompress(self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let reader: Box<dyn Read + '_> = match self {
+            Self::None => Box::new(data),
+            #[cfg(feature = "deflate")]
+            Self::Deflate => Box::new(flate2::read::DeflateDecoder::new(data)),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(data)?),
+        };
+        let mut decompressed = Vec::new();
+        reader
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        if decompressed.len() > limit {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+        Ok(decompressed)
+    }
+}
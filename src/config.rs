@@ -1,30 +1,145 @@
+use std::time::Duration;
+
+use crate::{compression::Compression, scheduler::Priority};
+
 #[derive(Copy, Clone, Debug)]
-/// Config struct for `WebSocketMultiplexor<T>`.
+/// Config struct for `WebSocketMultiplexor`.
 pub struct Config {
-    /// Frames larger than this size will be dropped.
+    /// Largest frame we send or accept, including the frame header, at
+    /// least 256 bytes.
+    /// Writes to vended streams are split to fit, a stream receiving a
+    /// larger frame is reset. Larger frames outside any stream disconnect.
     pub max_frame_size: usize,
-    /// Buffer size when reading / writing to vended streams,
-    /// should be at least 512 bytes smaller than `max_frame_size`.
+    /// Maximum amount of data sent in a single frame when writing to
+    /// vended streams, capped to fit in `max_frame_size`. Streams take
+    /// turns sending frames, smaller frames let a stream's data overtake
+    /// another's large writes sooner.
     pub buf_size: usize,
+    /// Receive window advertised to the peer for each stream, in bytes.
+    /// The peer stops sending data on a stream once this many bytes are
+    /// waiting to be read by the application.
+    pub initial_window_size: u32,
     /// How many frames can we queue in our inner channel before
     /// we block.
     pub max_queued_frames: usize,
     /// How many pending connections do we queue waiting on
     /// `accept()` to be called.
     pub accept_queue_len: usize,
-    /// An identifier for this `WebSocketMultiplexor<T>`.
-    /// Used in tracing logs.
+    /// How often to ping the peer, `None` disables keepalive.
+    pub keepalive_interval: Option<Duration>,
+    /// How long a Syn for a port nothing is bound to waits for `bind()`
+    /// before being refused, `None` refuses it immediately. At most
+    /// `accept_queue_len` Syns wait at a time.
+    pub unbound_syn_timeout: Option<Duration>,
+    /// How long to wait for the peer to answer a ping.
+    pub keepalive_timeout: Duration,
+    /// How many pings in a row may go unanswered before the peer is
+    /// considered dead and the multiplexor disconnects.
+    pub keepalive_max_missed: u32,
+    /// Shortest wait before dialing again after a reconnecting
+    /// multiplexor failed to establish a transport, doubled after each
+    /// failure. See `WebSocketMultiplexor::reconnecting()`.
+    pub reconnect_min_backoff: Duration,
+    /// Longest wait between two attempts to establish a transport.
+    pub reconnect_max_backoff: Duration,
+    /// How long a resumable session survives without a transport before
+    /// disconnecting.
+    pub resume_timeout: Duration,
+    /// Codec compressing the data of streams we open, unless overridden by
+    /// `ConnectOptions::compression`. Streams fall back to
+    /// `Compression::None` if the peer does not support it.
+    pub compression: Compression,
+    /// Data frames smaller than this many bytes are sent uncompressed.
+    pub compression_threshold: usize,
+    /// An identifier for this `WebSocketMultiplexor`.
+    /// Used in tracing logs, and as the `mux` label of metrics with the
+    /// `metrics` feature.
     pub identifier: &'static str,
 }
 
+/// Options for `WebSocketMultiplexor::connect_with()`.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    /// Give up if the peer has not accepted the connection in time,
+    /// `None` waits forever.
+    pub timeout: Option<Duration>,
+    /// Connect from this port instead of a random one.
+    pub source_port: Option<u16>,
+    /// Receive window advertised to the peer for this stream, overrides
+    /// `Config::initial_window_size`.
+    pub initial_window_size: Option<u32>,
+    /// Opaque data passed to the accepting side along with the Syn.
+    pub metadata: Vec<u8>,
+    /// Codec compressing the data of this stream in both directions,
+    /// overrides `Config::compression`.
+    pub compression: Option<Compression>,
+    /// How what this stream sends is scheduled against other streams.
+    pub priority: Priority,
+}
+
+impl ConnectOptions {
+    /// Fail with `TimedOut` if the peer has not accepted the connection
+    /// within `timeout`
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Connect from `port` instead of a random port
+    #[must_use]
+    pub fn with_source_port(mut self, port: u16) -> Self {
+        self.source_port = Some(port);
+        self
+    }
+
+    /// Advertise a receive window of `window` bytes for this stream
+    #[must_use]
+    pub fn with_initial_window_size(mut self, window: u32) -> Self {
+        self.initial_window_size = Some(window);
+        self
+    }
+
+    /// Send `metadata` to the accepting side
+    #[must_use]
+    pub fn with_metadata(mut self, metadata: impl Into<Vec<u8>>) -> Self {
+        self.metadata = metadata.into();
+        self
+    }
+
+    /// Compress the data of this stream with `compression`
+    #[must_use]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Schedule what this stream sends with `priority`
+    #[must_use]
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
 impl Default for Config {
     /// Construct a default `Config`
     fn default() -> Self {
         Self {
             max_frame_size: 4 * 1024 * 1024,
-            buf_size: 1024 * 1024,
+            buf_size: 64 * 1024,
+            initial_window_size: 4 * 1024 * 1024,
             max_queued_frames: 256,
             accept_queue_len: 16,
+            keepalive_interval: None,
+            unbound_syn_timeout: None,
+            keepalive_timeout: Duration::from_secs(10),
+            keepalive_max_missed: 3,
+            reconnect_min_backoff: Duration::from_millis(100),
+            reconnect_max_backoff: Duration::from_secs(5),
+            resume_timeout: Duration::from_secs(30),
+            compression: Compression::None,
+            compression_threshold: 256,
             identifier: "",
         }
     }
@@ -37,4 +152,32 @@ impl Config {
         self.identifier = identifier;
         self
     }
+
+    /// Ping the peer every `interval` to detect when it goes away
+    #[must_use]
+    pub fn with_keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive_interval = Some(interval);
+        self
+    }
+
+    /// Keep resumable sessions alive for `timeout` without a transport
+    #[must_use]
+    pub fn with_resume_timeout(mut self, timeout: Duration) -> Self {
+        self.resume_timeout = timeout;
+        self
+    }
+
+    /// Compress the data of streams we open with `compression`
+    #[must_use]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Let Syns for unbound ports wait up to `timeout` for `bind()`
+    #[must_use]
+    pub fn with_unbound_syn_timeout(mut self, timeout: Duration) -> Self {
+        self.unbound_syn_timeout = Some(timeout);
+        self
+    }
 }
@@ -0,0 +1,169 @@
+use bytes::Buf;
+
+use crate::error::FrameError;
+
+/// Messages exchanged on the mux-level control channel, carried in
+/// `Control` frames.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Control {
+    /// Ask for the names of the listeners the peer exposes.
+    ListNames { id: u32 },
+    /// Answer to `ListNames`.
+    Names { id: u32, names: Vec<String> },
+    /// Ask for the ports of the listeners the peer has bound.
+    ListPorts { id: u32 },
+    /// Answer to `ListPorts`.
+    Ports { id: u32, ports: Vec<u16> },
+    /// Ask the peer to send `Bound` and `Unbound` from now on.
+    Subscribe,
+    /// The sender bound a listener to `port`.
+    Bound { port: u16 },
+    /// The sender's listener on `port` went away.
+    Unbound { port: u16 },
+    /// The answer to query `id` does not fit in a frame.
+    Failed { id: u32 },
+}
+
+impl Control {
+    const LIST_NAMES: u8 = 1;
+    const NAMES: u8 = 2;
+    const LIST_PORTS: u8 = 3;
+    const PORTS: u8 = 4;
+    const SUBSCRIBE: u8 = 5;
+    const BOUND: u8 = 6;
+    const UNBOUND: u8 = 7;
+    const FAILED: u8 = 8;
+
+    /// The query this message answers, if it is an answer.
+    pub fn answers(&self) -> Option<u32> {
+        match self {
+            Self::Names { id, .. } | Self::Ports { id, .. } | Self::Failed { id } => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        match self {
+            Self::ListNames { id } => {
+                encoded.push(Self::LIST_NAMES);
+                encoded.extend_from_slice(&id.to_be_bytes());
+            }
+            Self::Names { id, names } => {
+                encoded.push(Self::NAMES);
+                encoded.extend_from_slice(&id.to_be_bytes());
+                encode_strings(&mut encoded, names);
+            }
+            Self::ListPorts { id } => {
+                encoded.push(Self::LIST_PORTS);
+                encoded.extend_from_slice(&id.to_be_bytes());
+            }
+            Self::Ports { id, ports } => {
+                encoded.push(Self::PORTS);
+                encoded.extend_from_slice(&id.to_be_bytes());
+                let count = u16::try_from(ports.len()).unwrap_or(u16::MAX);
+                encoded.extend_from_slice(&count.to_be_bytes());
+                for port in ports.iter().take(usize::from(count)) {
+                    encoded.extend_from_slice(&port.to_be_bytes());
+                }
+            }
+            Self::Subscribe => encoded.push(Self::SUBSCRIBE),
+            Self::Bound { port } => {
+                encoded.push(Self::BOUND);
+                encoded.extend_from_slice(&port.to_be_bytes());
+            }
+            Self::Unbound { port } => {
+                encoded.push(Self::UNBOUND);
+                encoded.extend_from_slice(&port.to_be_bytes());
+            }
+            Self::Failed { id } => {
+                encoded.push(Self::FAILED);
+                encoded.extend_from_slice(&id.to_be_bytes());
+            }
+        }
+        encoded
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, FrameError> {
+        let truncated = FrameError::Truncated(data.len());
+        let mut buf = data;
+        if buf.remaining() < 1 {
+            return Err(truncated);
+        }
+        let kind = buf.get_u8();
+        match kind {
+            Self::SUBSCRIBE => return Ok(Self::Subscribe),
+            Self::BOUND | Self::UNBOUND => {
+                if buf.remaining() < 2 {
+                    return Err(truncated);
+                }
+                let port = buf.get_u16();
+                return Ok(if kind == Self::BOUND {
+                    Self::Bound { port }
+                } else {
+                    Self::Unbound { port }
+                });
+            }
+            Self::LIST_NAMES | Self::NAMES | Self::LIST_PORTS | Self::PORTS | Self::FAILED => {}
+            kind => return Err(FrameError::UnknownControl(kind)),
+        }
+        if buf.remaining() < 4 {
+            return Err(truncated);
+        }
+        let id = buf.get_u32();
+        match kind {
+            Self::LIST_NAMES => Ok(Self::ListNames { id }),
+            Self::NAMES => Ok(Self::Names {
+                id,
+                names: decode_strings(&mut buf).ok_or(truncated)?,
+            }),
+            Self::LIST_PORTS => Ok(Self::ListPorts { id }),
+            Self::FAILED => Ok(Self::Failed { id }),
+            _ => Ok(Self::Ports {
+                id,
+                ports: decode_ports(&mut buf).ok_or(truncated)?,
+            }),
+        }
+    }
+}
+
+/// Encode a list of strings as a u16 count followed by u16 length-prefixed
+/// strings.
+fn encode_strings(encoded: &mut Vec<u8>, strings: &[String]) {
+    let count = u16::try_from(strings.len()).unwrap_or(u16::MAX);
+    encoded.extend_from_slice(&count.to_be_bytes());
+    for string in strings.iter().take(usize::from(count)) {
+        let len = u16::try_from(string.len()).expect("string too long");
+        encoded.extend_from_slice(&len.to_be_bytes());
+        encoded.extend_from_slice(string.as_bytes());
+    }
+}
+
+fn decode_strings(buf: &mut &[u8]) -> Option<Vec<String>> {
+    if buf.remaining() < 2 {
+        return None;
+    }
+    let count = buf.get_u16();
+    let mut strings = Vec::with_capacity(usize::from(count));
+    for _ in 0..count {
+        if buf.remaining() < 2 {
+            return None;
+        }
+        let len = usize::from(buf.get_u16());
+        let string = buf.get(..len)?;
+        strings.push(String::from_utf8_lossy(string).into_owned());
+        buf.advance(len);
+    }
+    Some(strings)
+}
+
+fn decode_ports(buf: &mut &[u8]) -> Option<Vec<u16>> {
+    if buf.remaining() < 2 {
+        return None;
+    }
+    let count = usize::from(buf.get_u16());
+    if buf.remaining() < count * 2 {
+        return None;
+    }
+    Some((0..count).map(|_| buf.get_u16()).collect())
+}
@@ -0,0 +1,199 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    io,
+};
+
+/// Why a `WebSocketMultiplexor` disconnected, see
+/// `WebSocketMultiplexor::disconnect_reason()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DisconnectReason {
+    /// `close()` or `shutdown()` was called, or the multiplexor was dropped.
+    Closed,
+    /// The inner stream ended or failed with an error of this kind.
+    Transport(io::ErrorKind),
+    /// The peer stopped answering keepalive pings.
+    KeepaliveTimeout,
+    /// The peer closed the inner stream, with the close code and reason it
+    /// gave, if any.
+    RemoteClosed {
+        /// The close code, e.g. 1000 for a normal WebSocket closure.
+        code: Option<u16>,
+        /// The reason given by the peer, may be empty.
+        reason: String,
+    },
+    /// The peer violated the protocol.
+    Protocol(FrameError),
+    /// The peer speaks no protocol version we support, holds the newest
+    /// and oldest versions it offered.
+    Incompatible {
+        /// Newest protocol version the peer speaks.
+        version: u16,
+        /// Oldest protocol version the peer speaks.
+        min_version: u16,
+    },
+    /// The peer could not resume our session after reconnecting, or does
+    /// not support resumption. See `WebSocketMultiplexor::reconnecting()`.
+    SessionExpired,
+}
+
+/// Why a connection was reset, carried in Rst frames.
+///
+/// Errors returned by `connect()` and by streams reset by the peer wrap
+/// the reason, see `ResetReason::from_io_error()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ResetReason {
+    /// No reason given.
+    Unspecified,
+    /// Nothing listens on the port.
+    Refused,
+    /// The listener's accept queue is full.
+    QueueFull,
+    /// The peer is shutting down.
+    ShuttingDown,
+    /// A frame on the connection violated the protocol.
+    ProtocolError,
+    /// The application aborted the stream with this code.
+    Abort(u32),
+    /// A frame arrived out of sequence: frames were lost, duplicated or
+    /// reordered.
+    OutOfOrder {
+        /// Sequence number of the frame that was due.
+        expected: u32,
+        /// Sequence number of the frame that arrived.
+        received: u32,
+    },
+}
+
+impl ResetReason {
+    /// The reset reason wrapped by `error`, if it was caused by a reset.
+    #[must_use]
+    pub fn from_io_error(error: &io::Error) -> Option<Self> {
+        error.get_ref()?.downcast_ref::<Self>().copied()
+    }
+
+    /// The kind of error reported to the application for this reset.
+    #[must_use]
+    pub fn error_kind(self) -> io::ErrorKind {
+        match self {
+            Self::Unspecified => io::ErrorKind::ConnectionReset,
+            Self::Refused | Self::QueueFull => io::ErrorKind::ConnectionRefused,
+            Self::ShuttingDown | Self::Abort(_) => io::ErrorKind::ConnectionAborted,
+            Self::ProtocolError | Self::OutOfOrder { .. } => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl From<ResetReason> for io::Error {
+    fn from(reason: ResetReason) -> Self {
+        io::Error::new(reason.error_kind(), reason)
+    }
+}
+
+impl Display for ResetReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Unspecified => write!(f, "connection reset"),
+            Self::Refused => write!(f, "connection refused"),
+            Self::QueueFull => write!(f, "accept queue full"),
+            Self::ShuttingDown => write!(f, "peer shutting down"),
+            Self::ProtocolError => write!(f, "protocol error"),
+            Self::Abort(code) => write!(f, "aborted with code {code}"),
+            Self::OutOfOrder { expected, received } => {
+                write!(
+                    f,
+                    "frame {received} arrived out of order, expected {expected}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResetReason {}
+
+/// Something other than a valid frame was received from the peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FrameError {
+    /// A text message was received where only binary frames are expected.
+    UnexpectedText,
+    /// The frame is shorter than the frame header, holds the frame length.
+    Truncated(usize),
+    /// The frame carries an unknown flag.
+    UnknownFlag(u8),
+    /// The frame is larger than `Config::max_frame_size`, holds the limit.
+    Oversize(usize),
+    /// The frame was encoded with an unsupported format version.
+    BadVersion(u8),
+    /// The peer sent another frame before its HELLO.
+    MissingHello,
+    /// The control message is of an unknown kind.
+    UnknownControl(u8),
+    /// The frame failed to decrypt, it was corrupted or forged.
+    Undecryptable,
+    /// The HELLOs allow frames too small for the protocol, holds the
+    /// smaller limit.
+    FrameTooSmall(usize),
+}
+
+impl DisconnectReason {
+    /// The error reported to connections torn down by this disconnect.
+    pub(crate) fn error_kind(&self) -> io::ErrorKind {
+        match self {
+            Self::KeepaliveTimeout => io::ErrorKind::TimedOut,
+            Self::Protocol(_) | Self::Incompatible { .. } => io::ErrorKind::InvalidData,
+            Self::SessionExpired => io::ErrorKind::ConnectionReset,
+            _ => io::ErrorKind::BrokenPipe,
+        }
+    }
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Closed => write!(f, "multiplexor closed"),
+            Self::Transport(kind) => write!(f, "transport failed: {kind}"),
+            Self::KeepaliveTimeout => write!(f, "peer stopped answering keepalive pings"),
+            Self::RemoteClosed { code, reason } => {
+                write!(f, "peer closed the connection")?;
+                if let Some(code) = code {
+                    write!(f, " with code {code}")?;
+                }
+                if !reason.is_empty() {
+                    write!(f, ": {reason}")?;
+                }
+                Ok(())
+            }
+            Self::Protocol(error) => write!(f, "protocol error: {error}"),
+            Self::Incompatible {
+                version,
+                min_version,
+            } => write!(
+                f,
+                "peer speaks protocol versions {min_version} to {version} only"
+            ),
+            Self::SessionExpired => write!(f, "peer could not resume the session"),
+        }
+    }
+}
+
+impl std::error::Error for DisconnectReason {}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::UnexpectedText => write!(f, "unexpected text message"),
+            Self::Truncated(len) => write!(f, "truncated frame of {len} bytes"),
+            Self::UnknownFlag(flag) => write!(f, "unknown frame flag {flag}"),
+            Self::Oversize(max) => write!(f, "frame larger than {max} bytes"),
+            Self::BadVersion(version) => write!(f, "unsupported frame version {version}"),
+            Self::MissingHello => write!(f, "frame received before HELLO"),
+            Self::UnknownControl(kind) => write!(f, "unknown control message {kind}"),
+            Self::Undecryptable => write!(f, "frame failed to decrypt"),
+            Self::FrameTooSmall(max) => write!(f, "max frame size of {max} bytes too small"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
@@ -1,8 +1,20 @@
+use std::io;
+
 use bytes::Buf;
-use tracing::warn;
-use tungstenite::Message;
 
-#[derive(Debug)]
+use crate::{
+    compression::Compression,
+    control::Control,
+    error::{FrameError, ResetReason},
+    hello::{Features, Hello},
+};
+
+/// Version of the frame format, sent as the first byte of every frame.
+pub const VERSION: u8 = 1;
+/// Size of the encoded frame header: version, sport, dport, flag and seq.
+pub const HEADER_SIZE: usize = 1 + 2 + 2 + 1 + 4;
+
+#[derive(Copy, Clone, Debug)]
 pub enum Flag {
     Syn = 0,
     SynAck = 1,
@@ -10,8 +22,28 @@ pub enum Flag {
     Rst = 3,
     Fin = 4,
     Unset = 5,
+    WindowUpdate = 6,
+    Ping = 7,
+    Pong = 8,
+    Hello = 9,
+    Control = 10,
+    SessionAck = 11,
+    /// Data compressed with the codec identified by its first byte.
+    Compressed = 12,
+}
+
+/// Tags of the options following the window in a `Syn` frame, each
+/// encoded as tag u8, length u16 and value.
+#[derive(Copy, Clone, Debug)]
+pub enum SynOption {
+    Metadata = 1,
+    /// Name of the listener to connect to, the destination port is 0.
+    Name = 2,
+    /// Identifies the `Compression` both sides of the stream use.
+    Compression = 3,
 }
 
+#[derive(Clone)]
 pub struct Frame {
     pub sport: u16,
     pub dport: u16,
@@ -71,59 +103,247 @@ impl Frame {
             data: Vec::from(data),
         }
     }
+
+    /// Data compressed by `codec`, see `Compression::compress()`.
+    pub fn new_compressed(
+        sport: u16,
+        dport: u16,
+        seq: u32,
+        codec: Compression,
+        compressed: &[u8],
+    ) -> Self {
+        let mut data = Vec::with_capacity(1 + compressed.len());
+        data.push(codec.id());
+        data.extend_from_slice(compressed);
+        Self {
+            sport,
+            dport,
+            flag: Flag::Compressed,
+            seq,
+            data,
+        }
+    }
+
+    /// Turn a `Compressed` frame into the data frame it carries, failing
+    /// with `InvalidData` if its codec was not agreed on with the peer, is
+    /// corrupt or decompresses to more than `limit` bytes.
+    pub fn decompressed(&self, features: Features, limit: usize) -> io::Result<Self> {
+        let codec = self
+            .data
+            .first()
+            .and_then(|id| Compression::from_id(*id))
+            .filter(|codec| *codec != Compression::None && features.contains(codec.feature()))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+        Ok(Self {
+            sport: self.sport,
+            dport: self.dport,
+            flag: Flag::Unset,
+            seq: self.seq,
+            data: codec.decompress(&self.data[1..], limit)?,
+        })
+    }
+
+    pub fn new_window_update(sport: u16, dport: u16, seq: u32, increment: u32) -> Self {
+        Self {
+            sport,
+            dport,
+            flag: Flag::WindowUpdate,
+            seq,
+            data: Vec::from(increment.to_be_bytes()),
+        }
+    }
+
+    pub(crate) fn new_hello(hello: &Hello) -> Self {
+        Self {
+            sport: 0,
+            dport: 0,
+            flag: Flag::Hello,
+            seq: 0,
+            data: hello.encode(),
+        }
+    }
+
+    /// Attach a reason to a `Rst` frame.
+    #[must_use]
+    pub fn with_reset_reason(mut self, reason: ResetReason) -> Self {
+        self.data = match reason {
+            ResetReason::Unspecified => vec![0],
+            ResetReason::Refused => vec![1],
+            ResetReason::QueueFull => vec![2],
+            ResetReason::ShuttingDown => vec![3],
+            ResetReason::ProtocolError => vec![4],
+            ResetReason::Abort(code) => {
+                let mut data = vec![5];
+                data.extend_from_slice(&code.to_be_bytes());
+                data
+            }
+            ResetReason::OutOfOrder { expected, received } => {
+                let mut data = vec![6];
+                data.extend_from_slice(&expected.to_be_bytes());
+                data.extend_from_slice(&received.to_be_bytes());
+                data
+            }
+        };
+        self
+    }
+
+    /// Read the reason carried by a `Rst` frame.
+    pub fn reset_reason(&self) -> ResetReason {
+        match self.data.first() {
+            Some(1) => ResetReason::Refused,
+            Some(2) => ResetReason::QueueFull,
+            Some(3) => ResetReason::ShuttingDown,
+            Some(4) => ResetReason::ProtocolError,
+            Some(5) => self
+                .data
+                .get(1..5)
+                .and_then(|code| code.try_into().ok())
+                .map_or(ResetReason::Unspecified, |code| {
+                    ResetReason::Abort(u32::from_be_bytes(code))
+                }),
+            Some(6) => match (self.data.get(1..5), self.data.get(5..9)) {
+                (Some(expected), Some(received)) => ResetReason::OutOfOrder {
+                    expected: u32::from_be_bytes(expected.try_into().unwrap()),
+                    received: u32::from_be_bytes(received.try_into().unwrap()),
+                },
+                _ => ResetReason::Unspecified,
+            },
+            _ => ResetReason::Unspecified,
+        }
+    }
+
+    pub(crate) fn new_control(control: &Control) -> Self {
+        Self {
+            sport: 0,
+            dport: 0,
+            flag: Flag::Control,
+            seq: 0,
+            data: control.encode(),
+        }
+    }
+
+    /// Acknowledge `received` frames of a resumable session, see
+    /// `Frame::is_resent()`.
+    pub fn new_session_ack(received: u64) -> Self {
+        Self {
+            sport: 0,
+            dport: 0,
+            flag: Flag::SessionAck,
+            seq: 0,
+            data: Vec::from(received.to_be_bytes()),
+        }
+    }
+
+    /// Read the count carried by a `SessionAck` frame.
+    pub fn session_ack(&self) -> Option<u64> {
+        let bytes = self.data.get(..std::mem::size_of::<u64>())?;
+        Some(u64::from_be_bytes(bytes.try_into().ok()?))
+    }
+
+    /// Whether the frame is counted and resent after a resumable session
+    /// reconnects. Frames only meaningful on the transport they were sent
+    /// on are not.
+    pub fn is_resent(&self) -> bool {
+        !matches!(
+            self.flag,
+            Flag::Hello | Flag::Ping | Flag::Pong | Flag::SessionAck
+        )
+    }
+
+    /// Size of the frame once encoded.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_SIZE + self.data.len()
+    }
+
+    /// Attach a receive window to a `Syn` or `SynAck` frame.
+    #[must_use]
+    pub fn with_window(mut self, window: u32) -> Self {
+        self.data = Vec::from(window.to_be_bytes());
+        self
+    }
+
+    /// Append an option to a `Syn` frame, after its window.
+    #[must_use]
+    pub fn with_option(mut self, tag: SynOption, value: &[u8]) -> Self {
+        let len = u16::try_from(value.len()).expect("Syn option too long");
+        self.data.push(tag as u8);
+        self.data.extend_from_slice(&len.to_be_bytes());
+        self.data.extend_from_slice(value);
+        self
+    }
+
+    /// Read an option carried by a `Syn` frame.
+    pub fn option(&self, tag: SynOption) -> Option<&[u8]> {
+        let mut options = self.data.get(std::mem::size_of::<u32>()..)?;
+        while options.len() >= 3 {
+            let len = usize::from(u16::from_be_bytes([options[1], options[2]]));
+            let value = options.get(3..3 + len)?;
+            if options[0] == tag as u8 {
+                return Some(value);
+            }
+            options = &options[3 + len..];
+        }
+        None
+    }
+
+    /// Read the window carried by a `Syn`, `SynAck` or `WindowUpdate` frame.
+    pub fn window(&self) -> Option<u32> {
+        let bytes = self.data.get(..std::mem::size_of::<u32>())?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    }
 }
 
-impl From<Frame> for Message {
+impl From<Frame> for Vec<u8> {
     #[tracing::instrument(skip_all, level = "trace")]
-    fn from(mut frame: Frame) -> Message {
-        let size = std::mem::size_of::<u16>()
-            + std::mem::size_of::<u16>()
-            + std::mem::size_of::<Flag>()
-            + std::mem::size_of::<u32>()
-            + frame.data.len();
-        let mut encoded = Vec::with_capacity(size);
+    fn from(mut frame: Frame) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(HEADER_SIZE + frame.data.len());
+        encoded.push(VERSION);
         encoded.extend_from_slice(&frame.sport.to_be_bytes());
         encoded.extend_from_slice(&frame.dport.to_be_bytes());
         encoded.extend_from_slice(&(frame.flag as u8).to_be_bytes());
         encoded.extend_from_slice(&frame.seq.to_be_bytes());
         encoded.append(&mut frame.data);
-        Message::Binary(encoded)
+        encoded
     }
 }
 
-impl TryFrom<Message> for Frame {
-    type Error = std::array::TryFromSliceError;
+impl TryFrom<Vec<u8>> for Frame {
+    type Error = FrameError;
     #[tracing::instrument(skip_all, level = "trace")]
-    fn try_from(value: Message) -> Result<Self, Self::Error> {
-        match value {
-            // TODO: Note that we do not correctly implement RFC6455, which requires
-            // handling of control frames.
-            Message::Binary(data) => {
-                let mut data = bytes::Bytes::from(data);
-                let sport = data.get_u16();
-                let dport = data.get_u16();
-                let flag = match data.get_u8() {
-                    0 => Flag::Syn,
-                    1 => Flag::SynAck,
-                    2 => Flag::Ack,
-                    3 => Flag::Rst,
-                    4 => Flag::Fin,
-                    5 => Flag::Unset,
-                    _ => {
-                        warn!("Invalid flag value");
-                        Flag::Unset
-                    }
-                };
-                let seq = data.get_u32();
-                Ok(Self {
-                    sport,
-                    dport,
-                    flag,
-                    seq,
-                    data: Vec::from(data),
-                })
-            }
-            _ => unreachable!("Only binary messages should be sent by client"),
+    fn try_from(data: Vec<u8>) -> Result<Self, Self::Error> {
+        if data.len() < HEADER_SIZE {
+            return Err(FrameError::Truncated(data.len()));
         }
+        let mut data = bytes::Bytes::from(data);
+        let version = data.get_u8();
+        if version != VERSION {
+            return Err(FrameError::BadVersion(version));
+        }
+        let sport = data.get_u16();
+        let dport = data.get_u16();
+        let flag = match data.get_u8() {
+            0 => Flag::Syn,
+            1 => Flag::SynAck,
+            2 => Flag::Ack,
+            3 => Flag::Rst,
+            4 => Flag::Fin,
+            5 => Flag::Unset,
+            6 => Flag::WindowUpdate,
+            7 => Flag::Ping,
+            8 => Flag::Pong,
+            9 => Flag::Hello,
+            10 => Flag::Control,
+            11 => Flag::SessionAck,
+            12 => Flag::Compressed,
+            flag => return Err(FrameError::UnknownFlag(flag)),
+        };
+        let seq = data.get_u32();
+        Ok(Self {
+            sport,
+            dport,
+            flag,
+            seq,
+            data: Vec::from(data),
+        })
     }
 }
@@ -0,0 +1,203 @@
+use std::ops::{BitAnd, BitOr};
+
+use bytes::Buf;
+
+use crate::{
+    config::Config,
+    error::{DisconnectReason, FrameError},
+};
+
+/// Protocol version spoken by this implementation.
+pub(crate) const PROTOCOL_VERSION: u16 = 1;
+/// Oldest protocol version this implementation can still speak.
+pub(crate) const MIN_PROTOCOL_VERSION: u16 = 1;
+/// Smallest `max_frame_size` a peer may announce, leaves room for frames
+/// without data and a Syn without metadata.
+pub(crate) const MIN_FRAME_SIZE: u32 = 256;
+
+/// Optional protocol features, agreed on during the HELLO exchange.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    /// Credit-based per-stream flow control.
+    pub const FLOW_CONTROL: Self = Self(1 << 0);
+    /// Mux-level keepalive pings.
+    pub const KEEPALIVE: Self = Self(1 << 1);
+    /// Listeners addressed by name, see `WebSocketMultiplexor::bind_named()`.
+    pub const NAMED_SERVICES: Self = Self(1 << 2);
+    /// Remote listener enumeration and bind notifications, see
+    /// `WebSocketMultiplexor::remote_listeners()`.
+    pub const REMOTE_LISTENERS: Self = Self(1 << 3);
+    /// Transparent session resumption after reconnecting, see
+    /// `WebSocketMultiplexor::reconnecting()`. Only offered by resumable
+    /// multiplexors.
+    pub const RESUME: Self = Self(1 << 4);
+    /// Data compressed with `Compression::Deflate`.
+    pub const DEFLATE: Self = Self(1 << 5);
+    /// Data compressed with `Compression::Zstd`.
+    pub const ZSTD: Self = Self(1 << 6);
+
+    /// Features supported by this implementation.
+    pub(crate) fn supported() -> Self {
+        let features =
+            Self::FLOW_CONTROL | Self::KEEPALIVE | Self::NAMED_SERVICES | Self::REMOTE_LISTENERS;
+        #[cfg(feature = "deflate")]
+        let features = features | Self::DEFLATE;
+        #[cfg(feature = "zstd")]
+        let features = features | Self::ZSTD;
+        features
+    }
+
+    /// Whether all features in `other` are also in `self`.
+    #[must_use]
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The raw feature bits.
+    #[must_use]
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+impl BitOr for Features {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Features {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+/// Parameters agreed on with the peer, see
+/// `WebSocketMultiplexor::negotiated()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+    /// Protocol version both peers speak.
+    pub version: u16,
+    /// Features both peers support.
+    pub features: Features,
+    /// Largest frame either peer may send, the smaller of both limits.
+    pub max_frame_size: usize,
+}
+
+/// Payload of the HELLO frame each peer sends before any other frame.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Hello {
+    pub version: u16,
+    pub min_version: u16,
+    pub features: Features,
+    pub max_frame_size: u32,
+    /// Sent by resumable multiplexors only.
+    pub session: Option<SessionHello>,
+}
+
+/// Session part of the HELLO payload of resumable multiplexors.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SessionHello {
+    /// Chosen by the dialing side, 0 tells the dialer its session is gone.
+    pub id: u64,
+    /// Whether this transport resumes the session, rather than starting it.
+    pub resume: bool,
+    /// How many resent frames the sender has received in this session.
+    pub received: u64,
+}
+
+impl SessionHello {
+    const SIZE: usize = 8 + 1 + 8;
+}
+
+impl Hello {
+    /// Size of the encoded HELLO payload, without the session part.
+    const SIZE: usize = 2 + 2 + 4 + 4;
+
+    pub fn new(config: &Config) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features: Features::supported(),
+            max_frame_size: u32::try_from(config.max_frame_size).unwrap_or(u32::MAX),
+            session: None,
+        }
+    }
+
+    /// Offer session resumption.
+    #[must_use]
+    pub fn with_session(mut self, session: SessionHello) -> Self {
+        self.features = self.features | Features::RESUME;
+        self.session = Some(session);
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(Self::SIZE + SessionHello::SIZE);
+        encoded.extend_from_slice(&self.version.to_be_bytes());
+        encoded.extend_from_slice(&self.min_version.to_be_bytes());
+        encoded.extend_from_slice(&self.features.bits().to_be_bytes());
+        encoded.extend_from_slice(&self.max_frame_size.to_be_bytes());
+        if let Some(session) = self.session {
+            encoded.extend_from_slice(&session.id.to_be_bytes());
+            encoded.push(u8::from(session.resume));
+            encoded.extend_from_slice(&session.received.to_be_bytes());
+        }
+        encoded
+    }
+
+    pub fn decode(mut data: &[u8]) -> Result<Self, FrameError> {
+        if data.len() < Self::SIZE {
+            return Err(FrameError::Truncated(data.len()));
+        }
+        let mut hello = Self {
+            version: data.get_u16(),
+            min_version: data.get_u16(),
+            features: Features(data.get_u32()),
+            max_frame_size: data.get_u32(),
+            session: None,
+        };
+        if hello.features.contains(Features::RESUME) {
+            if data.len() < SessionHello::SIZE {
+                return Err(FrameError::Truncated(Self::SIZE + data.len()));
+            }
+            hello.session = Some(SessionHello {
+                id: data.get_u64(),
+                resume: data.get_u8() != 0,
+                received: data.get_u64(),
+            });
+        }
+        Ok(hello)
+    }
+
+    /// Agree on parameters with the peer's HELLO.
+    ///
+    /// # Errors
+    /// Fails with `Incompatible` if no protocol version is spoken by both
+    /// peers and `FrameError::FrameTooSmall` if either allows frames
+    /// smaller than `MIN_FRAME_SIZE`.
+    pub fn negotiate(&self, peer: &Self) -> Result<Negotiated, DisconnectReason> {
+        let version = self.version.min(peer.version);
+        if version < self.min_version || version < peer.min_version {
+            return Err(DisconnectReason::Incompatible {
+                version: peer.version,
+                min_version: peer.min_version,
+            });
+        }
+        let max_frame_size = self.max_frame_size.min(peer.max_frame_size);
+        if max_frame_size < MIN_FRAME_SIZE {
+            return Err(DisconnectReason::Protocol(FrameError::FrameTooSmall(
+                max_frame_size as usize,
+            )));
+        }
+        Ok(Negotiated {
+            version,
+            features: self.features & peer.features,
+            max_frame_size: max_frame_size as usize,
+        })
+    }
+}
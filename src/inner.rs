@@ -3,8 +3,8 @@ use std::{
     fmt::{Debug, Formatter, Result as FmtResult},
     io,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
     },
 };
 
@@ -12,39 +12,100 @@ extern crate async_channel;
 
 use futures_util::sink::SinkExt;
 use futures_util::stream::StreamExt;
-use futures_util::{Sink as FutureSink, Stream as FutureStream};
 use tokio::{
-    io::DuplexStream,
-    sync::{mpsc, watch, RwLock},
+    sync::{broadcast, mpsc, oneshot, watch, Notify, RwLock},
+    time::{sleep, sleep_until, timeout, timeout_at, Instant},
 };
-use tracing::{debug, error, trace};
-use tungstenite::Message;
+use tracing::{debug, error, trace, warn};
 
 use crate::{
+    compression::Compression,
     config::Config,
-    frame::{Flag, Frame},
+    control::Control,
+    error::{DisconnectReason, FrameError, ResetReason},
+    frame::{Flag, Frame, SynOption, HEADER_SIZE},
+    hello::{Hello, Negotiated},
+    listener::ListenerEvent,
+    scheduler::{FrameSender, Priority, Scheduler},
+    session::{Dialer, Session},
     socket::MuxSocket,
+    stats::Counters,
+    stream::MuxStream,
+    transport::{FrameSink, FrameStream},
 };
 
-type PortPair = (u16, u16);
+pub(crate) type PortPair = (u16, u16);
 
-pub(crate) struct WebSocketMultiplexorInner<Sink, Stream> {
+/// The port a Syn is for, looking it up by name if its destination port
+/// is 0.
+fn syn_port(frame: &Frame, named_listeners: &HashMap<String, u16>) -> Option<u16> {
+    if frame.dport == 0 {
+        let name = String::from_utf8_lossy(frame.option(SynOption::Name)?);
+        trace!("Syn for name {:?}", name);
+        named_listeners.get(name.as_ref()).copied()
+    } else {
+        Some(frame.dport)
+    }
+}
+
+pub(crate) struct WebSocketMultiplexorInner {
     pub config: Config,
     pub connected: AtomicBool,
-    pub port_connections: RwLock<HashMap<PortPair, Arc<MuxSocket<Sink, Stream>>>>,
-    pub port_listeners: RwLock<HashMap<u16, async_channel::Sender<DuplexStream>>>,
+    pub port_connections: RwLock<HashMap<PortPair, Arc<MuxSocket>>>,
+    pub port_listeners: RwLock<HashMap<u16, async_channel::Sender<MuxStream>>>,
+    /// Ports of the listeners bound by name.
+    pub named_listeners: RwLock<HashMap<String, u16>>,
     /// The sender for the watch channel that is used to signal that the mux is connected or not.
     pub watch_connected_send: watch::Sender<bool>,
     /// The sender of ports that may be freed.
     pub may_close_listeners: mpsc::UnboundedSender<u16>,
     /// The sender of connection ports that may be freed.
     pub may_close_connections: mpsc::UnboundedSender<PortPair>,
-    pub send: RwLock<mpsc::Sender<Frame>>,
+    pub send: RwLock<FrameSender>,
+    /// The receiving end of `send`, held by the writer of the current transport.
+    pub queue: tokio::sync::Mutex<Scheduler>,
+    /// Priorities of the connections not scheduled with the default one.
+    pub priorities: Mutex<HashMap<PortPair, Priority>>,
+    /// Held by the reader of the current transport.
+    pub reader_slot: tokio::sync::Mutex<()>,
+    /// Set if the mux survives transport failures.
+    pub session: Option<Session>,
     /// The sender for the watch channel that is used to signal that the mux is running or not.
     pub running: watch::Sender<bool>,
+    /// Set once a graceful shutdown has started, no new connections are made.
+    pub shutting_down: AtomicBool,
+    /// Notified whenever a connection is freed.
+    pub connections_freed: Notify,
+    /// The sender for the watch channel that is used to signal that the writer has
+    /// flushed all queued frames and closed the transport.
+    pub flushed: watch::Sender<bool>,
+    /// Why the mux disconnected, set once.
+    pub disconnect_reason: Mutex<Option<DisconnectReason>>,
+    /// The sender for the watch channel carrying the nonce of the last Pong received.
+    pub last_pong: watch::Sender<u32>,
+    /// The sender for the watch channel carrying the parameters agreed on with the peer.
+    pub negotiated: watch::Sender<Option<Negotiated>>,
+    /// Control queries waiting for the peer's answer, by query id.
+    pub pending_queries: Mutex<HashMap<u32, oneshot::Sender<Control>>>,
+    pub next_query_id: AtomicU32,
+    /// Set once the peer asked to be told when we bind or unbind ports.
+    pub peer_subscribed: AtomicBool,
+    /// Set once we asked the peer to tell us when it binds or unbinds ports.
+    pub subscribed: AtomicBool,
+    /// The sender of the peer's bind and unbind notifications, taken on
+    /// disconnect to end subscriptions.
+    pub listener_events: Mutex<Option<broadcast::Sender<ListenerEvent>>>,
+    /// Syns for ports nothing is bound to yet, with when to give up on them.
+    pub parked_syns: Mutex<HashMap<PortPair, (Frame, Instant)>>,
+    /// What the mux did so far, see `WebSocketMultiplexor::stats()`.
+    pub counters: Counters,
+    /// The peer's static public key, set by the handshake of encrypted
+    /// multiplexors.
+    #[cfg(feature = "noise")]
+    pub peer_public_key: std::sync::OnceLock<[u8; 32]>,
 }
 
-impl<Sink, Stream> Debug for WebSocketMultiplexorInner<Sink, Stream> {
+impl Debug for WebSocketMultiplexorInner {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         f.debug_struct("WebSocketMultiplexorInner")
             .field("id", &self.config.identifier)
@@ -53,23 +114,101 @@ impl<Sink, Stream> Debug for WebSocketMultiplexorInner<Sink, Stream> {
     }
 }
 
-impl<Sink, Stream> Drop for WebSocketMultiplexorInner<Sink, Stream> {
+impl Drop for WebSocketMultiplexorInner {
     fn drop(&mut self) {
         self.watch_connected_send.send_replace(false);
         debug!("drop {:?}", self);
     }
 }
 
-impl<Sink, Stream> WebSocketMultiplexorInner<Sink, Stream>
-where
-    Sink: FutureSink<Message, Error = tungstenite::Error> + Unpin + 'static,
-    Stream: FutureStream<Item = tungstenite::Result<Message>> + Unpin + 'static,
-{
-    #[tracing::instrument(skip(recv, frame_sink), level = "trace")]
+impl WebSocketMultiplexorInner {
+    /// Record why the mux disconnected, unless already disconnected, and
+    /// signal the disconnect to all tasks.
+    pub fn disconnect(&self, reason: DisconnectReason) {
+        trace!("{:?}", reason);
+        self.disconnect_reason.lock().unwrap().get_or_insert(reason);
+        self.watch_connected_send.send_replace(false);
+    }
+
+    /// Whether `disconnect()` was called.
+    pub fn is_connected(&self) -> bool {
+        *self.watch_connected_send.borrow()
+    }
+
+    /// Handle the failure of the transport of `generation`. Resumable
+    /// sessions wait for a new transport, see `supervise_session()`, other
+    /// multiplexors disconnect.
+    pub fn transport_failed(&self, generation: u64, reason: DisconnectReason) {
+        match &self.session {
+            Some(session)
+                if matches!(
+                    reason,
+                    DisconnectReason::Transport(_) | DisconnectReason::KeepaliveTimeout
+                ) =>
+            {
+                session.lose(generation, reason);
+            }
+            _ => self.disconnect(reason),
+        }
+    }
+
+    /// The current transport generation, always 0 unless resumable.
+    pub fn generation(&self) -> u64 {
+        self.session
+            .as_ref()
+            .map_or(0, |session| session.generation.borrow().0)
+    }
+
+    /// Wait until the transport of `generation` is replaced, never returns
+    /// unless resumable.
+    async fn superseded(&self, generation: u64) {
+        match &self.session {
+            Some(session) => session.superseded(generation).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Start sending and receiving frames over a new transport, replacing
+    /// the current one of a resumable session.
+    pub fn attach(self: &Arc<Self>, sink: FrameSink, stream: FrameStream) {
+        let mut hello = Hello::new(&self.config);
+        let mut generation = 0;
+        if let Some(session) = &self.session {
+            let (current, session_hello) = session.attach();
+            generation = current;
+            hello = hello.with_session(session_hello);
+        }
+        let attached = Instant::now();
+        let (peer_received_send, peer_received) = oneshot::channel();
+        tokio::spawn(self.clone().frame_writer_sender(
+            sink,
+            generation,
+            Frame::new_hello(&hello),
+            peer_received,
+        ));
+        tokio::spawn(self.clone().frame_reader_sender(
+            stream,
+            generation,
+            hello,
+            attached,
+            peer_received_send,
+        ));
+    }
+
+    /// Once disconnected, nothing flushes queued frames anymore.
+    fn writer_exited(&self) {
+        if !self.is_connected() {
+            self.flushed.send_replace(true);
+        }
+    }
+
+    #[tracing::instrument(skip(frame_sink, hello, peer_received), level = "trace")]
     pub async fn frame_writer_sender(
         self: Arc<Self>,
-        mut recv: mpsc::Receiver<Frame>,
-        mut frame_sink: Sink,
+        mut frame_sink: FrameSink,
+        generation: u64,
+        hello: Frame,
+        peer_received: oneshot::Receiver<u64>,
     ) {
         let mut running = self.running.subscribe();
         let mut connected = self.watch_connected_send.subscribe();
@@ -78,19 +217,64 @@ where
                 error!("Error {:?} receiving running state", error);
             }
         }
+        // Wait for the writer of the previous transport to exit
+        let mut recv = tokio::select! {
+            recv = self.queue.lock() => recv,
+            () = self.superseded(generation) => return,
+        };
+
+        trace!("Send {:?}", hello);
+        self.counters.sent(&hello);
+        if let Err(error) = frame_sink.send(hello).await {
+            error!("Error {:?} sending Hello", error);
+            self.transport_failed(generation, DisconnectReason::Transport(error.kind()));
+            self.writer_exited();
+            return;
+        }
+
+        if let Some(session) = &self.session {
+            // Send again what the peer missed before anything new
+            let received = tokio::select! {
+                received = peer_received => received,
+                () = self.superseded(generation) => return,
+                _ = connected.wait_for(|connected| !connected) => {
+                    self.writer_exited();
+                    return;
+                }
+            };
+            let Ok(received) = received else {
+                trace!("Reader exited before the peer's Hello");
+                return;
+            };
+            let frames = session.unreceived(received);
+            debug!("Sending {} frames again", frames.len());
+            for frame in frames {
+                self.counters.sent(&frame);
+                let sent = tokio::select! {
+                    sent = frame_sink.send(frame) => sent,
+                    () = self.superseded(generation) => return,
+                };
+                if let Err(error) = sent {
+                    error!("Error {:?} sending to stream", error);
+                    self.transport_failed(generation, DisconnectReason::Transport(error.kind()));
+                    self.writer_exited();
+                    return;
+                }
+            }
+        }
 
         loop {
             if !*connected.borrow() {
                 trace!("Running false");
                 break;
             }
-            let frame = tokio::select! {
-                res = recv.recv() => {
+            let (frame, waited) = tokio::select! {
+                res = recv.next(|key| self.priority(key)) => {
                     if let Some(value) = res {
                         value
                     } else {
                         error!("Error {:?} reading from stream", res);
-                        self.watch_connected_send.send_replace(false);
+                        self.disconnect(DisconnectReason::Closed);
                         break;
                     }
                 }
@@ -98,26 +282,105 @@ where
                     trace!("Connected changed");
                     continue;
                 }
+                () = self.superseded(generation) => {
+                    trace!("Transport replaced");
+                    return;
+                }
             };
-            match frame.try_into() {
-                Ok(message) => {
-                    if let Err(error) = frame_sink.send(message).await {
-                        error!("Error {:?} sending to stream", error);
-                        self.watch_connected_send.send_replace(false);
-                        break;
-                    }
+            if let Some(session) = &self.session {
+                if frame.is_resent() {
+                    session.sending(&frame);
                 }
-                Err(error) => {
-                    error!("Error {:?} converting frame to message", error);
-                    self.watch_connected_send.send_replace(false);
+            }
+            self.counters.sent(&frame);
+            self.counters.queue_wait(waited);
+            let sent = tokio::select! {
+                sent = frame_sink.send(frame) => sent,
+                () = self.superseded(generation) => return,
+            };
+            if let Err(error) = sent {
+                error!("Error {:?} sending to stream", error);
+                self.transport_failed(generation, DisconnectReason::Transport(error.kind()));
+                self.writer_exited();
+                return;
+            }
+        }
+
+        if self.shutting_down.load(Ordering::Relaxed) {
+            // Flush what was queued before the shutdown completed
+            while let Some((frame, waited)) = recv.try_next(|key| self.priority(key)) {
+                self.counters.sent(&frame);
+                self.counters.queue_wait(waited);
+                if let Err(error) = frame_sink.send(frame).await {
+                    error!("Error {:?} flushing to stream", error);
                     break;
                 }
             }
+            if let Err(error) = frame_sink.close().await {
+                warn!("Error {:?} closing stream", error);
+            }
+        }
+        self.flushed.send_replace(true);
+    }
+
+    /// Read the next frame from the transport of `generation`, or `None`
+    /// once it failed or was replaced, or the mux disconnected.
+    async fn next_frame(
+        &self,
+        frame_stream: &mut FrameStream,
+        generation: u64,
+        connected: &mut watch::Receiver<bool>,
+    ) -> Option<Frame> {
+        loop {
+            if !*connected.borrow() {
+                trace!("Running false");
+                return None;
+            }
+            tokio::select! {
+                res = frame_stream.next() => {
+                    match res {
+                        Some(Ok(frame)) => {
+                            self.counters.received(&frame);
+                            return Some(frame);
+                        }
+                        Some(Err(reason)) => {
+                            if matches!(reason, DisconnectReason::Protocol(_)) {
+                                self.counters.decode_error();
+                            }
+                            debug!("Inner stream closed: {}", reason);
+                            self.transport_failed(generation, reason);
+                            return None;
+                        }
+                        None => {
+                            debug!("Inner stream ended");
+                            self.transport_failed(
+                                generation,
+                                DisconnectReason::Transport(io::ErrorKind::UnexpectedEof),
+                            );
+                            return None;
+                        }
+                    }
+                }
+                _ = connected.changed() => {
+                    trace!("Connected changed");
+                }
+                () = self.superseded(generation) => {
+                    trace!("Transport replaced");
+                    return None;
+                }
+            }
         }
     }
 
-    #[tracing::instrument(skip(frame_stream), level = "trace")]
-    pub async fn frame_reader_sender(self: Arc<Self>, mut frame_stream: Stream) {
+    #[tracing::instrument(skip(frame_stream, hello, attached, peer_received), level = "trace")]
+    pub async fn frame_reader_sender(
+        self: Arc<Self>,
+        mut frame_stream: FrameStream,
+        generation: u64,
+        hello: Hello,
+        attached: Instant,
+        peer_received: oneshot::Sender<u64>,
+    ) {
         let mut running = self.running.subscribe();
         let mut connected = self.watch_connected_send.subscribe();
         while !*running.borrow() {
@@ -126,60 +389,186 @@ where
             }
         }
 
-        loop {
-            if !*connected.borrow() {
-                trace!("Running false");
-                break;
+        let Some(frame) = self
+            .next_frame(&mut frame_stream, generation, &mut connected)
+            .await
+        else {
+            return;
+        };
+        let hello = self.process_hello(&frame, generation, &hello);
+        if hello.is_ok() {
+            self.counters.handshake(attached.elapsed());
+        }
+        match hello {
+            Ok(Some(received)) => {
+                peer_received.send(received).ok();
             }
-            let frame: Frame = tokio::select! {
-                res = frame_stream.next() => {
-                    if let Some(Ok(message)) = res {
-                        match Frame::try_from(message) {
-                            Ok(frame) => frame,
-                            Err(error) => {
-                                error!("Error {:?} converting message to frame", error);
-                                self.watch_connected_send.send_replace(false);
-                                break;
+            Ok(None) => {}
+            Err(reason) => {
+                self.counters.decode_error();
+                self.disconnect(reason);
+                return;
+            }
+        }
+        // Let the reader of the previous transport finish its last frame
+        let _reader_slot = tokio::select! {
+            slot = self.reader_slot.lock() => slot,
+            () = self.superseded(generation) => return,
+        };
+
+        while let Some(frame) = self
+            .next_frame(&mut frame_stream, generation, &mut connected)
+            .await
+        {
+            if let Some(session) = &self.session {
+                if frame.is_resent() {
+                    match session.received(generation) {
+                        None => break,
+                        Some(Some(received)) => {
+                            trace!("Acknowledge {} frames", received);
+                            if let Err(error) = self
+                                .send
+                                .write()
+                                .await
+                                .send(Frame::new_session_ack(received))
+                                .await
+                            {
+                                error!("Error {:?} sending SessionAck", error);
                             }
                         }
-                    } else {
-                        error!("Error {:?} reading from framed_reader", res);
-                        self.watch_connected_send.send_replace(false);
-                        break;
+                        Some(None) => {}
                     }
                 }
-                _ = connected.changed() => {
-                    trace!("Connected changed");
-                    continue;
+            }
+            if frame.encoded_len() > self.config.max_frame_size {
+                warn!(
+                    "Frame of {} bytes exceeds max_frame_size",
+                    frame.encoded_len()
+                );
+                if matches!(
+                    frame.flag,
+                    Flag::Hello | Flag::Ping | Flag::Pong | Flag::SessionAck | Flag::Control
+                ) {
+                    // Not part of any connection, nothing to reset
+                    self.counters.decode_error();
+                    self.disconnect(DisconnectReason::Protocol(FrameError::Oversize(
+                        self.config.max_frame_size,
+                    )));
+                    break;
                 }
-            };
-            if matches!(frame.flag, Flag::Syn)
-                && self.port_listeners.read().await.contains_key(&frame.dport)
-            {
-                trace!("Syn received for listener, vending MuxSocket");
-                let socket = MuxSocket::new(self.clone(), frame.dport, frame.sport, true);
-                self.port_connections
-                    .write()
+                let socket = self
+                    .port_connections
+                    .read()
                     .await
-                    .insert((frame.dport, frame.sport), socket.clone());
-                socket.recv_frame(frame).await;
-            } else if let Some(socket) = self
+                    .get(&(frame.dport, frame.sport))
+                    .cloned();
+                self.reset_frame(socket, &frame).await;
+                continue;
+            }
+            match frame.flag {
+                Flag::Hello => {
+                    warn!("Ignoring repeated Hello");
+                    continue;
+                }
+                Flag::Ping => {
+                    trace!("Ping {}, sending Pong", frame.seq);
+                    if let Err(error) = self
+                        .send
+                        .write()
+                        .await
+                        .send(Frame::new_no_data(0, 0, Flag::Pong, frame.seq))
+                        .await
+                    {
+                        error!("Error {:?} sending Pong", error);
+                    }
+                    continue;
+                }
+                Flag::Pong => {
+                    trace!("Pong {}", frame.seq);
+                    self.last_pong.send_replace(frame.seq);
+                    continue;
+                }
+                Flag::SessionAck => {
+                    if let (Some(session), Some(received)) = (&self.session, frame.session_ack()) {
+                        trace!("Peer acknowledged {} frames", received);
+                        session.acked(received);
+                    }
+                    continue;
+                }
+                Flag::Control => {
+                    if let Err(reason) = self.process_control(&frame).await {
+                        self.counters.decode_error();
+                        self.disconnect(reason);
+                        break;
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let socket = self
                 .port_connections
                 .read()
                 .await
                 .get(&(frame.dport, frame.sport))
-            {
+                .cloned();
+            let frame = if matches!(frame.flag, Flag::Compressed) {
+                let features = self
+                    .negotiated
+                    .borrow()
+                    .map(|negotiated| negotiated.features)
+                    .unwrap_or_default();
+                let limit = self.config.max_frame_size.saturating_sub(HEADER_SIZE);
+                match frame.decompressed(features, limit) {
+                    Ok(frame) => frame,
+                    Err(error) => {
+                        warn!("Error {:?} decompressing {:?}, sending Rst", error, frame);
+                        self.reset_frame(socket, &frame).await;
+                        continue;
+                    }
+                }
+            } else {
+                frame
+            };
+            let listener = if matches!(frame.flag, Flag::Syn) {
+                self.find_listener(&frame).await
+            } else {
+                None
+            };
+            if let Some(listener) = listener {
+                self.accept_syn(frame, listener).await;
+            } else if let Some(socket) = socket {
                 trace!("Frame received for active socket {:?}", socket);
                 socket.recv_frame(frame).await;
-            } else if !matches!(frame.flag, Flag::Rst) {
+            } else if matches!(frame.flag, Flag::Syn)
+                && self.config.unbound_syn_timeout.is_some()
+                && !self.shutting_down.load(Ordering::Relaxed)
+            {
+                self.park_syn(frame).await;
+            } else if matches!(frame.flag, Flag::Rst) {
+                // The peer gave up on a parked Syn
+                self.parked_syns
+                    .lock()
+                    .unwrap()
+                    .remove(&(frame.dport, frame.sport));
+            } else {
                 trace!(
                     "Frame received for unknown (dport, sport) ({}, {}), sending Rst",
                     frame.dport,
                     frame.sport
                 );
+                if matches!(frame.flag, Flag::Syn) {
+                    self.counters.syn_refused();
+                }
+                let reason = if self.shutting_down.load(Ordering::Relaxed) {
+                    ResetReason::ShuttingDown
+                } else if matches!(frame.flag, Flag::Syn) {
+                    ResetReason::Refused
+                } else {
+                    ResetReason::Unspecified
+                };
                 let framed_writer = self.send.write().await;
                 if let Err(error) = framed_writer
-                    .send(Frame::new_reply(&frame, Flag::Rst, 0))
+                    .send(Frame::new_reply(&frame, Flag::Rst, 0).with_reset_reason(reason))
                     .await
                 {
                     error!("Error {:?} sending Rst", error);
@@ -188,6 +577,426 @@ where
         }
     }
 
+    /// The priority of the connection from `key`, local port first.
+    fn priority(&self, key: PortPair) -> Priority {
+        self.priorities
+            .lock()
+            .unwrap()
+            .get(&key)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Reset the connection `frame` was received on for violating the
+    /// protocol.
+    async fn reset_frame(&self, socket: Option<Arc<MuxSocket>>, frame: &Frame) {
+        self.counters.decode_error();
+        if let Some(socket) = socket {
+            socket.reset(ResetReason::ProtocolError).await;
+        } else if let Err(error) = self
+            .send
+            .write()
+            .await
+            .send(
+                Frame::new_reply(frame, Flag::Rst, 0).with_reset_reason(ResetReason::ProtocolError),
+            )
+            .await
+        {
+            error!("Error {:?} sending Rst", error);
+        }
+    }
+
+    /// Vend a `MuxSocket` for a Syn to `listener`, or refuse it if the
+    /// listener's accept queue is full.
+    async fn accept_syn(
+        self: &Arc<Self>,
+        frame: Frame,
+        (port, listener): (u16, async_channel::Sender<MuxStream>),
+    ) {
+        if listener.is_full() {
+            debug!("Accept queue of port {} full, sending Rst", port);
+            self.counters.syn_refused();
+            if let Err(error) = self
+                .send
+                .write()
+                .await
+                .send(
+                    Frame::new_reply(&frame, Flag::Rst, 0)
+                        .with_reset_reason(ResetReason::QueueFull),
+                )
+                .await
+            {
+                error!("Error {:?} sending Rst", error);
+            }
+            return;
+        }
+        trace!("Syn received for listener, vending MuxSocket");
+        // Compress what we send like the peer does, if we can
+        let features = self
+            .negotiated
+            .borrow()
+            .map(|negotiated| negotiated.features)
+            .unwrap_or_default();
+        let compression = frame
+            .option(SynOption::Compression)
+            .and_then(|id| Compression::from_id(*id.first()?))
+            .filter(|compression| features.contains(compression.feature()))
+            .unwrap_or_default();
+        let socket = MuxSocket::new(
+            self.clone(),
+            frame.dport,
+            frame.sport,
+            port,
+            true,
+            self.config.initial_window_size,
+            compression,
+        );
+        self.port_connections
+            .write()
+            .await
+            .insert((frame.dport, frame.sport), socket.clone());
+        socket.recv_frame(frame).await;
+    }
+
+    /// Hold on to a Syn for a port nothing is bound to yet, until `bind()`
+    /// completes it or `Config::unbound_syn_timeout` refuses it.
+    async fn park_syn(self: &Arc<Self>, frame: Frame) {
+        let Some(wait) = self.config.unbound_syn_timeout else {
+            return;
+        };
+        let key = (frame.dport, frame.sport);
+        let deadline = Instant::now() + wait;
+        {
+            let mut parked_syns = self.parked_syns.lock().unwrap();
+            if parked_syns.contains_key(&key) {
+                trace!("Syn for {:?} already parked", key);
+                return;
+            }
+            if parked_syns.len() < self.config.accept_queue_len {
+                debug!("No listener for port {}, parking Syn", frame.dport);
+                parked_syns.insert(key, (frame, deadline));
+                drop(parked_syns);
+                tokio::spawn(self.clone().expire_parked_syn(key, deadline));
+                return;
+            }
+        }
+        debug!("Too many parked Syns, sending Rst");
+        self.counters.syn_refused();
+        if let Err(error) = self
+            .send
+            .write()
+            .await
+            .send(Frame::new_reply(&frame, Flag::Rst, 0).with_reset_reason(ResetReason::QueueFull))
+            .await
+        {
+            error!("Error {:?} sending Rst", error);
+        }
+    }
+
+    /// Refuse the Syn parked at `key` if nothing was bound by `deadline`.
+    async fn expire_parked_syn(self: Arc<Self>, key: PortPair, deadline: Instant) {
+        sleep_until(deadline).await;
+        let frame = {
+            let mut parked_syns = self.parked_syns.lock().unwrap();
+            match parked_syns.get(&key) {
+                // Replaced by a later Syn with its own deadline
+                Some((_, parked_deadline)) if *parked_deadline == deadline => {
+                    parked_syns.remove(&key).map(|(frame, _)| frame)
+                }
+                _ => None,
+            }
+        };
+        if let Some(frame) = frame {
+            debug!("Parked Syn for port {} expired, sending Rst", frame.dport);
+            self.counters.syn_refused();
+            if let Err(error) = self
+                .send
+                .write()
+                .await
+                .send(
+                    Frame::new_reply(&frame, Flag::Rst, 0).with_reset_reason(ResetReason::Refused),
+                )
+                .await
+            {
+                error!("Error {:?} sending Rst", error);
+            }
+        }
+    }
+
+    /// Complete the parked Syns a newly bound listener can take.
+    pub async fn unpark_syns(self: &Arc<Self>) {
+        if self.parked_syns.lock().unwrap().is_empty() {
+            return;
+        }
+        let port_listeners = self.port_listeners.read().await.clone();
+        let named_listeners = self.named_listeners.read().await.clone();
+        let ready: Vec<_> = {
+            let mut parked_syns = self.parked_syns.lock().unwrap();
+            let keys: Vec<PortPair> = parked_syns
+                .iter()
+                .filter(|(_, (frame, _))| {
+                    syn_port(frame, &named_listeners)
+                        .is_some_and(|port| port_listeners.contains_key(&port))
+                })
+                .map(|(key, _)| *key)
+                .collect();
+            keys.iter()
+                .filter_map(|key| parked_syns.remove(key))
+                .collect()
+        };
+        for (frame, _) in ready {
+            let port = syn_port(&frame, &named_listeners).unwrap();
+            debug!("Completing parked Syn for port {}", port);
+            self.accept_syn(frame, (port, port_listeners[&port].clone()))
+                .await;
+        }
+    }
+
+    /// Find the listener a Syn is for, by name if its destination port is 0.
+    async fn find_listener(
+        &self,
+        frame: &Frame,
+    ) -> Option<(u16, async_channel::Sender<MuxStream>)> {
+        let port = syn_port(frame, &*self.named_listeners.read().await)?;
+        let listener = self.port_listeners.read().await.get(&port)?.clone();
+        Some((port, listener))
+    }
+
+    /// Answer the peer's control queries and deliver its answers to ours.
+    async fn process_control(&self, frame: &Frame) -> Result<(), DisconnectReason> {
+        let control = Control::decode(&frame.data).map_err(DisconnectReason::Protocol)?;
+        trace!("{:?}", control);
+        if let Some(id) = control.answers() {
+            if let Some(sender) = self.pending_queries.lock().unwrap().remove(&id) {
+                sender.send(control).ok();
+            }
+            return Ok(());
+        }
+        let answer = match control {
+            Control::ListNames { id } => {
+                let mut names: Vec<String> =
+                    self.named_listeners.read().await.keys().cloned().collect();
+                names.sort_unstable();
+                Control::Names { id, names }
+            }
+            Control::ListPorts { id } => {
+                let mut ports: Vec<u16> =
+                    self.port_listeners.read().await.keys().copied().collect();
+                ports.sort_unstable();
+                Control::Ports { id, ports }
+            }
+            Control::Subscribe => {
+                self.peer_subscribed.store(true, Ordering::SeqCst);
+                return Ok(());
+            }
+            Control::Bound { port } => {
+                self.publish_listener_event(ListenerEvent::Bound(port));
+                return Ok(());
+            }
+            Control::Unbound { port } => {
+                self.publish_listener_event(ListenerEvent::Unbound(port));
+                return Ok(());
+            }
+            Control::Names { .. } | Control::Ports { .. } | Control::Failed { .. } => {
+                unreachable!("answers are handled above")
+            }
+        };
+        match self.send_control(&answer).await {
+            Err(error) if error.kind() == io::ErrorKind::InvalidInput => {
+                if let Some(id) = answer.answers() {
+                    debug!("Answer to query {} does not fit in a frame", id);
+                    if let Err(error) = self.send_control(&Control::Failed { id }).await {
+                        error!("Error {:?} failing query {}", error, id);
+                    }
+                }
+            }
+            Err(error) => error!("Error {:?} answering {:?}", error, answer),
+            Ok(()) => {}
+        }
+        Ok(())
+    }
+
+    fn publish_listener_event(&self, event: ListenerEvent) {
+        if let Some(sender) = self.listener_events.lock().unwrap().as_ref() {
+            // No receivers is fine
+            sender.send(event).ok();
+        }
+    }
+
+    /// Tell the peer we bound or unbound a port, if it asked.
+    pub async fn notify_listener(&self, control: Control) {
+        if self.peer_subscribed.load(Ordering::SeqCst) {
+            if let Err(error) = self.send_control(&control).await {
+                error!("Error {:?} sending {:?}", error, control);
+            }
+        }
+    }
+
+    /// Queue `control` for the peer.
+    ///
+    /// # Errors
+    /// Returns `InvalidInput` if it does not fit in a frame and `BrokenPipe`
+    /// if the writer is gone.
+    pub async fn send_control(&self, control: &Control) -> io::Result<()> {
+        trace!("Send {:?}", control);
+        let frame = Frame::new_control(control);
+        if frame.encoded_len() > self.max_frame_size() {
+            debug!("{:?} exceeds max_frame_size", control);
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        self.send
+            .write()
+            .await
+            .send(frame)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    /// Wait for the HELLO exchange to complete.
+    pub async fn wait_negotiated(&self) -> io::Result<Negotiated> {
+        let mut negotiated = self.negotiated.subscribe();
+        let mut connected = self.watch_connected_send.subscribe();
+        tokio::select! {
+            res = negotiated.wait_for(Option::is_some) => {
+                if let Ok(negotiated) = res {
+                    if let Some(negotiated) = *negotiated {
+                        return Ok(negotiated);
+                    }
+                }
+            }
+            _ = connected.wait_for(|connected| !connected) => {}
+        }
+        Err(io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    /// Send a control query to the peer and wait for its answer.
+    ///
+    /// # Errors
+    /// Fails if the query cannot be sent, the connection closes before the
+    /// peer answers, or with `InvalidData` if its answer does not fit in a
+    /// frame.
+    pub async fn query(&self, query: impl FnOnce(u32) -> Control) -> io::Result<Control> {
+        let id = self.next_query_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending_queries.lock().unwrap().insert(id, sender);
+        // Pending queries are dropped on disconnect, check after inserting
+        if !self.connected.load(Ordering::Relaxed) {
+            self.pending_queries.lock().unwrap().remove(&id);
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        }
+        if let Err(error) = self.send_control(&query(id)).await {
+            self.pending_queries.lock().unwrap().remove(&id);
+            return Err(error);
+        }
+        match receiver.await {
+            Ok(Control::Failed { .. }) => {
+                debug!("Peer failed to answer query {}", id);
+                Err(io::Error::from(io::ErrorKind::InvalidData))
+            }
+            Ok(answer) => Ok(answer),
+            Err(_) => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        }
+    }
+
+    /// Agree on protocol parameters with the peer's first frame, which
+    /// must be its Hello, and the `hello` we sent on this transport.
+    fn process_hello(
+        &self,
+        frame: &Frame,
+        generation: u64,
+        hello: &Hello,
+    ) -> Result<Option<u64>, DisconnectReason> {
+        if !matches!(frame.flag, Flag::Hello) {
+            error!("Received {:?} before Hello", frame);
+            return Err(DisconnectReason::Protocol(FrameError::MissingHello));
+        }
+        let peer = Hello::decode(&frame.data).map_err(DisconnectReason::Protocol)?;
+        trace!("Peer {:?}", peer);
+        let negotiated = hello.negotiate(&peer)?;
+        debug!("Negotiated {:?}", negotiated);
+        self.negotiated.send_replace(Some(negotiated));
+        match &self.session {
+            Some(session) => session.peer_hello(generation, peer.session),
+            None => Ok(None),
+        }
+    }
+
+    /// Largest frame we may send, as agreed on with the peer.
+    pub fn max_frame_size(&self) -> usize {
+        self.negotiated
+            .borrow()
+            .map_or(self.config.max_frame_size, |negotiated| {
+                negotiated.max_frame_size
+            })
+    }
+
+    /// Largest amount of stream data sent in a single frame.
+    pub fn max_data_size(&self) -> usize {
+        self.config
+            .buf_size
+            .max(1)
+            .min(self.max_frame_size().saturating_sub(HEADER_SIZE))
+    }
+
+    /// Ping the peer every `interval`, disconnecting once it misses
+    /// `keepalive_max_missed` Pongs in a row.
+    #[tracing::instrument(level = "debug")]
+    pub async fn keepalive(self: Arc<Self>, interval: std::time::Duration) {
+        let mut running = self.running.subscribe();
+        let mut connected = self.watch_connected_send.subscribe();
+        let mut last_pong = self.last_pong.subscribe();
+        while !*running.borrow() {
+            if running.changed().await.is_err() {
+                return;
+            }
+        }
+
+        let mut nonce: u32 = 0;
+        let mut missed: u32 = 0;
+        loop {
+            tokio::select! {
+                () = sleep(interval) => {}
+                _ = connected.wait_for(|connected| !connected) => {
+                    trace!("Not connected, stop keepalive");
+                    return;
+                }
+            }
+            nonce = nonce.wrapping_add(1);
+            let generation = self.generation();
+            trace!("Send Ping {}", nonce);
+            if let Err(error) = self
+                .send
+                .write()
+                .await
+                .send(Frame::new_no_data(0, 0, Flag::Ping, nonce))
+                .await
+            {
+                error!("Error {:?} sending Ping", error);
+                return;
+            }
+            let answered = timeout(
+                self.config.keepalive_timeout,
+                last_pong.wait_for(|pong| *pong == nonce),
+            )
+            .await;
+            match answered {
+                Ok(Ok(_)) => missed = 0,
+                Ok(Err(_)) => return,
+                // Count misses afresh on a new transport
+                Err(_) if self.generation() != generation => missed = 0,
+                Err(_) => {
+                    missed += 1;
+                    debug!("Ping {} unanswered, {} missed", nonce, missed);
+                    if missed >= self.config.keepalive_max_missed {
+                        error!("Peer stopped answering pings, dropping transport");
+                        self.transport_failed(generation, DisconnectReason::KeepaliveTimeout);
+                        missed = 0;
+                    }
+                }
+            }
+        }
+    }
+
     /// Process `may_close_listeners_recv` channel.
     /// Use in a `select!` statement.
     async fn process_may_close_listeners_once(
@@ -197,8 +1006,15 @@ where
         if let Some(dport) = may_close_listeners_recv.recv().await {
             debug!("Freeing listener at port {}", dport);
             let mut port_listeners = self.port_listeners.write().await;
-            if let Some(listener) = port_listeners.remove(&dport) {
+            let removed = port_listeners.remove(&dport);
+            drop(port_listeners);
+            if let Some(listener) = removed {
                 listener.close();
+                self.named_listeners
+                    .write()
+                    .await
+                    .retain(|_, port| *port != dport);
+                self.notify_listener(Control::Unbound { port: dport }).await;
             }
         }
     }
@@ -210,9 +1026,28 @@ where
         may_close_connections_recv: &mut mpsc::UnboundedReceiver<PortPair>,
     ) {
         if let Some((dport, sport)) = may_close_connections_recv.recv().await {
-            debug!("Freeing connection from port {} to port {}", sport, dport);
-            let mut port_connections = self.port_connections.write().await;
-            port_connections.remove(&(dport, sport));
+            let socket = self
+                .port_connections
+                .read()
+                .await
+                .get(&(dport, sport))
+                .cloned();
+            let Some(socket) = socket else {
+                return;
+            };
+            // The application dropped the stream without shutting it down
+            if socket.shutdown_write() {
+                trace!("Send Fin for dropped {:?}", socket);
+                socket.send_fin().await;
+            }
+            if socket.is_closed() {
+                debug!("Freeing connection from port {} to port {}", sport, dport);
+                let mut port_connections = self.port_connections.write().await;
+                port_connections.remove(&(dport, sport));
+                drop(port_connections);
+                self.priorities.lock().unwrap().remove(&(dport, sport));
+                self.connections_freed.notify_waiters();
+            }
         }
     }
 
@@ -242,22 +1077,98 @@ where
         }
 
         self.connected.store(false, Ordering::Relaxed);
+        let error_kind = self
+            .disconnect_reason
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(io::ErrorKind::BrokenPipe, DisconnectReason::error_kind);
 
         for (_, connection) in self.port_connections.write().await.drain() {
-            trace!("Send rst to {:?}", connection);
-            if connection.rst.send(true).is_err() {
-                error!("Error sending rst to connection {:?}", connection);
-            }
+            trace!("Reset {:?}", connection);
+            connection.close(Some(error_kind));
             if let Some(sender) = connection.external_stream_sender.write().await.as_ref() {
                 trace!("Send Error to {:?} external_stream_reader", connection);
-                if let Err(error) = sender
-                    .send(Err(io::Error::from(io::ErrorKind::BrokenPipe)))
-                    .await
-                {
+                if let Err(error) = sender.send(Err(io::Error::from(error_kind))).await {
                     error!("Error {:?} dropping port_connections", error);
                 }
             }
         }
         self.port_listeners.write().await.clear();
+        self.named_listeners.write().await.clear();
+        self.pending_queries.lock().unwrap().clear();
+        self.listener_events.lock().unwrap().take();
+        self.parked_syns.lock().unwrap().clear();
+        self.priorities.lock().unwrap().clear();
+        if self.queue.try_lock().is_ok() {
+            // No writer left to flush
+            self.flushed.send_replace(true);
+        }
+    }
+
+    /// Keep a resumable session alive: wait for its transport to be lost,
+    /// establish a new one with `dial` if given, and disconnect if none is
+    /// attached within `Config::resume_timeout`.
+    #[tracing::instrument(skip(dial), level = "debug")]
+    pub async fn supervise_session(self: Arc<Self>, mut dial: Option<Dialer>) {
+        let Some(session) = &self.session else {
+            return;
+        };
+        let mut generation = session.generation.subscribe();
+        let mut connected = self.watch_connected_send.subscribe();
+        loop {
+            tokio::select! {
+                _ = generation.wait_for(|(_, live)| !live) => {}
+                _ = connected.wait_for(|connected| !connected) => return,
+            }
+            let deadline = Instant::now() + self.config.resume_timeout;
+            let resumed = async {
+                match dial.as_mut() {
+                    Some(dial) => self.redial(session, dial, deadline).await,
+                    None => timeout_at(deadline, generation.wait_for(|(_, live)| *live))
+                        .await
+                        .is_ok(),
+                }
+            };
+            tokio::select! {
+                resumed = resumed => {
+                    if !resumed {
+                        error!("No transport within resume_timeout, disconnecting");
+                        self.disconnect(session.lost_reason());
+                        return;
+                    }
+                }
+                _ = connected.wait_for(|connected| !connected) => return,
+            }
+        }
+    }
+
+    /// Dial until a transport is established, backing off between attempts.
+    /// Returns false if none was by `deadline`.
+    async fn redial(
+        self: &Arc<Self>,
+        session: &Session,
+        dial: &mut Dialer,
+        deadline: Instant,
+    ) -> bool {
+        let mut backoff = self.config.reconnect_min_backoff;
+        loop {
+            match timeout_at(deadline, dial()).await {
+                Ok(Ok((sink, stream))) => {
+                    self.attach(sink, stream);
+                    return true;
+                }
+                Ok(Err(error)) => {
+                    debug!("Error {:?} establishing transport", error);
+                    session.set_lost_reason(DisconnectReason::Transport(error.kind()));
+                }
+                Err(_) => return false,
+            }
+            if Instant::now() + backoff >= deadline {
+                return false;
+            }
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(self.config.reconnect_max_backoff);
+        }
     }
 }
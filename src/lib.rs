@@ -32,34 +32,63 @@
 
 #![warn(missing_docs)]
 
+mod compression;
 mod config;
+mod control;
+mod error;
 mod frame;
+mod hello;
 mod inner;
 mod listener;
+#[cfg(feature = "noise")]
+mod noise;
+mod scheduler;
+mod session;
 mod socket;
+mod stats;
+mod stream;
+mod transport;
 
 use std::{
     collections::HashMap,
+    error::Error,
     fmt::{Debug, Formatter, Result as FmtResult},
+    future::Future,
     io,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
     },
 };
 
 extern crate async_channel;
 use futures_util::{Sink as FutureSink, Stream as FutureStream};
 use rand::Rng;
-pub use tokio::io::DuplexStream;
-use tokio::sync::{mpsc, watch, RwLock};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{broadcast, mpsc, watch, Notify, RwLock},
+    time::{timeout_at, Instant},
+};
 use tracing::{debug, trace};
-use tungstenite::Message;
 
-pub use config::Config;
+pub use compression::Compression;
+pub use config::{Config, ConnectOptions};
+use control::Control;
+pub use error::{DisconnectReason, FrameError, ResetReason};
+pub use hello::{Features, Negotiated};
 use inner::WebSocketMultiplexorInner;
-pub use listener::MuxListener;
+pub use listener::{Incoming, ListenerEvent, MuxListener, PeerInfo};
+#[cfg(feature = "noise")]
+pub use noise::NoiseConfig;
+pub use scheduler::Priority;
+use session::Session;
+pub use session::Sessions;
 use socket::MuxSocket;
+use stats::Counters;
+pub use stats::{ConnectionState, ConnectionStats, MuxStats};
+pub use stream::{MuxStream, OwnedReadHalf, OwnedWriteHalf};
+use transport::{FrameSink, FrameStream};
+pub use transport::{MessageContent, TransportMessage};
 
 /// Result type returned by `bind()`, `accept()`, and `connect()`.
 pub type Result<T> = std::result::Result<T, io::Error>;
@@ -70,11 +99,11 @@ pub type Result<T> = std::result::Result<T, io::Error>;
 /// When the `WebSocketMultiplexor` is dropped, it will send RST to all open
 /// connections and listeners.
 #[derive(Clone)]
-pub struct WebSocketMultiplexor<Sink, Stream> {
-    inner: Arc<WebSocketMultiplexorInner<Sink, Stream>>,
+pub struct WebSocketMultiplexor {
+    inner: Arc<WebSocketMultiplexorInner>,
 }
 
-impl<Sink, Stream> Debug for WebSocketMultiplexor<Sink, Stream> {
+impl Debug for WebSocketMultiplexor {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         f.debug_struct("WebSocketMultiplexor")
             .field("id", &self.inner.config.identifier)
@@ -83,51 +112,275 @@ impl<Sink, Stream> Debug for WebSocketMultiplexor<Sink, Stream> {
     }
 }
 
-impl<Sink, Stream> Drop for WebSocketMultiplexor<Sink, Stream> {
+impl Drop for WebSocketMultiplexor {
     fn drop(&mut self) {
         self.close();
         debug!("drop {:?}", self);
     }
 }
 
-impl<Sink, Stream> WebSocketMultiplexor<Sink, Stream> {
+impl WebSocketMultiplexor {
     /// Start processing the inner stream.
     ///
-    /// Only effective on a paused `WebSocketMultiplexor<T>`.
-    /// See `new_paused(inner: T, config: Config)`.
+    /// Only effective on a paused `WebSocketMultiplexor`.
+    /// See `new_paused(sink, stream, config)`.
     pub fn start(&self) {
         self.inner.running.send_replace(true);
     }
 
-    /// Shut down the `WebSocketMultiplexor<T>` instance and drop reference
+    /// Shut down the `WebSocketMultiplexor` instance and drop reference
     /// to the inner stream to close it.
     pub fn close(&self) {
-        self.inner.watch_connected_send.send_replace(false);
+        self.inner.disconnect(DisconnectReason::Closed);
     }
-}
 
-impl<Sink, Stream> WebSocketMultiplexor<Sink, Stream>
-where
-    Sink: FutureSink<Message, Error = tungstenite::Error> + Send + Unpin + 'static,
-    Stream: FutureStream<Item = tungstenite::Result<Message>> + Send + Unpin + 'static,
-{
-    /// Constructs a new `WebSocketMultiplexor<T>`.
-    pub fn new(sink: Sink, stream: Stream, config: Config) -> Self {
+    /// Gracefully shut down the `WebSocketMultiplexor` instance.
+    ///
+    /// Stops accepting new connections, resets those still connecting, sends
+    /// Fin on every open connection and waits for the peers to finish theirs. Queued frames are then
+    /// flushed and the inner stream is closed.
+    ///
+    /// # Errors
+    /// Returns `TimedOut` if `deadline` expired first, in which case the
+    /// remaining connections are reset.
+    #[tracing::instrument]
+    pub async fn shutdown(&self, deadline: Instant) -> Result<()> {
+        trace!("");
+        self.inner.shutting_down.store(true, Ordering::Relaxed);
+        for (_, listener) in self.inner.port_listeners.write().await.drain() {
+            listener.close();
+        }
+
+        let sockets: Vec<Arc<MuxSocket>> = self
+            .inner
+            .port_connections
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect();
+        let finished = timeout_at(deadline, async {
+            for socket in sockets {
+                if socket.is_connecting() {
+                    // Would never get to send Fin, nor hear one
+                    trace!("Reset half-open {:?}", socket);
+                    socket.reset_connecting(ResetReason::ShuttingDown).await;
+                } else if socket.shutdown_write() {
+                    trace!("Send Fin for {:?}", socket);
+                    socket.send_fin().await;
+                }
+            }
+            loop {
+                let freed = self.inner.connections_freed.notified();
+                if self.inner.port_connections.read().await.is_empty() {
+                    break;
+                }
+                freed.await;
+            }
+        })
+        .await
+        .is_ok();
+
+        if !finished {
+            debug!("Shutdown deadline expired, resetting connections");
+            let sockets: Vec<Arc<MuxSocket>> = self
+                .inner
+                .port_connections
+                .read()
+                .await
+                .values()
+                .cloned()
+                .collect();
+            for socket in sockets {
+                socket
+                    .abort(ResetReason::ShuttingDown, io::ErrorKind::ConnectionAborted)
+                    .await;
+            }
+        }
+
+        let mut flushed = self.inner.flushed.subscribe();
+        self.close();
+        let flushed = timeout_at(deadline, flushed.wait_for(|flushed| *flushed))
+            .await
+            .is_ok();
+        if finished && flushed {
+            Ok(())
+        } else {
+            Err(io::Error::from(io::ErrorKind::TimedOut))
+        }
+    }
+
+    /// Constructs a new `WebSocketMultiplexor` over a message-oriented
+    /// transport, such as the two halves of a split
+    /// `tokio_tungstenite::WebSocketStream`.
+    ///
+    /// Each frame is sent as one message, see `TransportMessage`.
+    pub fn new<Sink, Stream, M, E>(sink: Sink, stream: Stream, config: Config) -> Self
+    where
+        M: TransportMessage + Send + 'static,
+        Sink: FutureSink<M> + Send + 'static,
+        Sink::Error: Error + Send + Sync + 'static,
+        Stream: FutureStream<Item = std::result::Result<M, E>> + Send + 'static,
+        E: Error + Send + Sync + 'static,
+    {
+        let (sink, stream) = transport::from_messages(sink, stream);
         Self::new_running(sink, stream, config, true)
     }
 
-    /// Constructs a new paused `WebSocketMultiplexor<T>`.
+    /// Constructs a new paused `WebSocketMultiplexor` over a
+    /// message-oriented transport.
     ///
     /// This allows you to bind and listen on a bunch of ports before
     /// processing any packets from the inner stream, removing race conditions
     /// between bind and connect. Call `start()` to start processing the
     /// inner stream.
-    pub fn new_paused(sink: Sink, stream: Stream, config: Config) -> Self {
+    pub fn new_paused<Sink, Stream, M, E>(sink: Sink, stream: Stream, config: Config) -> Self
+    where
+        M: TransportMessage + Send + 'static,
+        Sink: FutureSink<M> + Send + 'static,
+        Sink::Error: Error + Send + Sync + 'static,
+        Stream: FutureStream<Item = std::result::Result<M, E>> + Send + 'static,
+        E: Error + Send + Sync + 'static,
+    {
+        let (sink, stream) = transport::from_messages(sink, stream);
+        Self::new_running(sink, stream, config, false)
+    }
+
+    /// Constructs a new `WebSocketMultiplexor` over a byte stream, such as
+    /// a `TcpStream` or `UnixStream`.
+    ///
+    /// Each frame is prefixed with its length.
+    pub fn from_io<T>(io: T, config: Config) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (sink, stream) = transport::from_io(io, &config);
+        Self::new_running(sink, stream, config, true)
+    }
+
+    /// Constructs a new paused `WebSocketMultiplexor` over a byte stream.
+    ///
+    /// See `new_paused(sink, stream, config)`.
+    pub fn from_io_paused<T>(io: T, config: Config) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (sink, stream) = transport::from_io(io, &config);
         Self::new_running(sink, stream, config, false)
     }
 
-    fn new_running(sink: Sink, stream: Stream, config: Config, running: bool) -> Self {
-        let (send, recv) = mpsc::channel(config.max_queued_frames);
+    /// Constructs a new `WebSocketMultiplexor` over a message-oriented
+    /// transport, encrypting and authenticating everything it sends.
+    ///
+    /// Runs a Noise handshake with the peer, which must use the other role
+    /// in `noise`, before any frame is sent. Each frame is then sealed in
+    /// one message, see `new()`. The peer's static key is available from
+    /// `peer_public_key()`.
+    ///
+    /// # Errors
+    /// Returns `PermissionDenied` if the peer's key does not match
+    /// `NoiseConfig::with_remote_public_key()` or the pre-shared keys
+    /// differ, and `InvalidData` if the peer does not speak the same
+    /// handshake. Wrap the call in a timeout to bound how long to wait for
+    /// the peer.
+    #[cfg(feature = "noise")]
+    pub async fn new_encrypted<Sink, Stream, M, E>(
+        sink: Sink,
+        stream: Stream,
+        config: Config,
+        noise: &NoiseConfig,
+    ) -> Result<Self>
+    where
+        M: TransportMessage + Send + 'static,
+        Sink: FutureSink<M> + Send + 'static,
+        Sink::Error: Error + Send + Sync + 'static,
+        Stream: FutureStream<Item = std::result::Result<M, E>> + Send + 'static,
+        E: Error + Send + Sync + 'static,
+    {
+        let (sink, stream) = transport::message_bytes(sink, stream);
+        Self::encrypted(sink, stream, config, noise).await
+    }
+
+    /// Constructs a new encrypted `WebSocketMultiplexor` over a byte
+    /// stream.
+    ///
+    /// See `new_encrypted()` and `from_io()`.
+    ///
+    /// # Errors
+    /// Returns the errors of `new_encrypted()`.
+    #[cfg(feature = "noise")]
+    pub async fn from_io_encrypted<T>(io: T, config: Config, noise: &NoiseConfig) -> Result<Self>
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let max_len = noise::sealed_len(config.max_frame_size);
+        let (sink, stream) = transport::io_bytes(io, &config, max_len);
+        Self::encrypted(sink, stream, config, noise).await
+    }
+
+    #[cfg(feature = "noise")]
+    async fn encrypted(
+        sink: transport::ByteSink,
+        stream: transport::ByteStream,
+        config: Config,
+        noise: &NoiseConfig,
+    ) -> Result<Self> {
+        let (sink, stream, peer_public_key) = noise::handshake(sink, stream, noise).await?;
+        debug!("Handshake with {:?} complete", peer_public_key);
+        let mux = Self::with_session(config, true, None);
+        mux.inner.peer_public_key.set(peer_public_key).ok();
+        mux.inner.attach(sink, stream);
+        Ok(mux)
+    }
+
+    /// Constructs a new `WebSocketMultiplexor` that survives failures of
+    /// its transport.
+    ///
+    /// `connect` is called for a message-oriented transport, see `new()`,
+    /// now and whenever the transport fails, with exponential backoff
+    /// between `Config::reconnect_min_backoff` and
+    /// `Config::reconnect_max_backoff`. Open streams carry on over the new
+    /// transport, frames the peer missed are sent again. The multiplexor
+    /// disconnects if no transport is established within
+    /// `Config::resume_timeout`.
+    ///
+    /// The peer must accept the transports with `Sessions::accept()`.
+    pub fn reconnecting<F, Fut, Sink, Stream, M, E>(connect: F, config: Config) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(Sink, Stream)>> + Send + 'static,
+        M: TransportMessage + Send + 'static,
+        Sink: FutureSink<M> + Send + 'static,
+        Sink::Error: Error + Send + Sync + 'static,
+        Stream: FutureStream<Item = std::result::Result<M, E>> + Send + 'static,
+        E: Error + Send + Sync + 'static,
+    {
+        let mut id = 0;
+        while id == 0 {
+            id = rand::thread_rng().gen();
+        }
+        let mux = Self::new_session(config, Session::new(id));
+        tokio::spawn(
+            mux.inner
+                .clone()
+                .supervise_session(Some(session::dialer(connect))),
+        );
+        mux
+    }
+
+    fn new_running(sink: FrameSink, stream: FrameStream, config: Config, running: bool) -> Self {
+        let mux = Self::with_session(config, running, None);
+        mux.inner.attach(sink, stream);
+        mux
+    }
+
+    /// A resumable multiplexor, without a transport yet.
+    fn new_session(config: Config, session: Session) -> Self {
+        Self::with_session(config, true, Some(session))
+    }
+
+    fn with_session(config: Config, running: bool, session: Option<Session>) -> Self {
+        let (send, queue) = scheduler::queue(config.max_queued_frames);
         let (watch_connected_send, watch_connected_recv) = watch::channel(true);
         let (running, _) = watch::channel(running);
         let (may_close_listeners_send, may_close_listeners_recv) = mpsc::unbounded_channel();
@@ -137,15 +390,36 @@ where
             connected: AtomicBool::from(true),
             port_connections: RwLock::from(HashMap::new()),
             port_listeners: RwLock::from(HashMap::new()),
+            named_listeners: RwLock::from(HashMap::new()),
             watch_connected_send,
             may_close_listeners: may_close_listeners_send,
             may_close_connections: may_close_connections_send,
             send: RwLock::from(send),
+            queue: tokio::sync::Mutex::from(queue),
+            priorities: Mutex::from(HashMap::new()),
+            reader_slot: tokio::sync::Mutex::from(()),
+            session,
             running,
+            shutting_down: AtomicBool::from(false),
+            connections_freed: Notify::new(),
+            flushed: watch::channel(false).0,
+            disconnect_reason: Mutex::from(None),
+            last_pong: watch::channel(0).0,
+            negotiated: watch::channel(None).0,
+            pending_queries: Mutex::from(HashMap::new()),
+            next_query_id: AtomicU32::new(0),
+            peer_subscribed: AtomicBool::from(false),
+            subscribed: AtomicBool::from(false),
+            parked_syns: Mutex::from(HashMap::new()),
+            listener_events: Mutex::from(Some(broadcast::channel(config.max_queued_frames).0)),
+            counters: Counters::new(config.identifier),
+            #[cfg(feature = "noise")]
+            peer_public_key: std::sync::OnceLock::new(),
         });
 
-        tokio::spawn(inner.clone().frame_writer_sender(recv, sink));
-        tokio::spawn(inner.clone().frame_reader_sender(stream));
+        if let Some(interval) = config.keepalive_interval {
+            tokio::spawn(inner.clone().keepalive(interval));
+        }
         tokio::spawn(inner.clone().handle_mux_state_change(
             watch_connected_recv,
             may_close_listeners_recv,
@@ -155,11 +429,19 @@ where
         Self { inner }
     }
 
-    /// Bind to port and return a `MuxListener<T>`.
+    /// Bind to port and return a `MuxListener`.
     #[tracing::instrument]
-    pub async fn bind(&self, port: u16) -> Result<MuxListener<Sink, Stream>> {
+    pub async fn bind(&self, port: u16) -> Result<MuxListener> {
+        let listener = self.bind_port(port).await?;
+        self.inner.unpark_syns().await;
+        Ok(listener)
+    }
+
+    async fn bind_port(&self, port: u16) -> Result<MuxListener> {
         trace!("");
-        if !self.inner.connected.load(Ordering::Relaxed) {
+        if !self.inner.connected.load(Ordering::Relaxed)
+            || self.inner.shutting_down.load(Ordering::Relaxed)
+        {
             return Err(io::Error::from(io::ErrorKind::ConnectionReset));
         }
         let mut port = port;
@@ -174,51 +456,354 @@ where
         }
         let (send, recv) = async_channel::bounded(self.inner.config.accept_queue_len);
         self.inner.port_listeners.write().await.insert(port, send);
+        self.inner.notify_listener(Control::Bound { port }).await;
         Ok(MuxListener::new(self.inner.clone(), port, recv))
     }
 
+    /// Bind to a random port reachable by `name` and return a `MuxListener`.
+    ///
+    /// The name is released when the listener is dropped.
+    ///
+    /// # Errors
+    /// Returns `AddrInUse` if `name` is already bound, `InvalidInput` if it
+    /// does not fit in a frame and `Unsupported` if the peer is known not to
+    /// support named services.
+    #[tracing::instrument]
+    pub async fn bind_named(&self, name: &str) -> Result<MuxListener> {
+        trace!("");
+        // Binding is local, only refuse once the HELLOs tell us the peer
+        // cannot look names up
+        if self
+            .negotiated()
+            .is_some_and(|negotiated| !negotiated.features.contains(Features::NAMED_SERVICES))
+        {
+            trace!("Peer does not support {:?}", Features::NAMED_SERVICES);
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+        // The name must fit in a Syn and in the answer to ListNames
+        let names = Control::Names {
+            id: 0,
+            names: vec![name.to_owned()],
+        };
+        if name.len() > usize::from(u16::MAX)
+            || frame::Frame::new_control(&names).encoded_len() > self.inner.max_frame_size()
+        {
+            trace!("Name of {} bytes too long", name.len());
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let mut named_listeners = self.inner.named_listeners.write().await;
+        if named_listeners.contains_key(name) {
+            trace!("named_listeners already contains {:?}", name);
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+        let listener = self.bind_port(0).await?;
+        named_listeners.insert(name.to_owned(), listener.port());
+        drop(named_listeners);
+        self.inner.unpark_syns().await;
+        Ok(listener)
+    }
+
     /// Connect to `port` on the remote end.
     #[tracing::instrument]
-    pub async fn connect(&self, port: u16) -> Result<DuplexStream> {
+    pub async fn connect(&self, port: u16) -> Result<MuxStream> {
+        self.connect_with(port, ConnectOptions::default()).await
+    }
+
+    /// Connect to `port` on the remote end with `options`.
+    ///
+    /// # Errors
+    /// Returns `TimedOut` if `options.timeout` expires before the peer
+    /// accepts, `AddrInUse` if `options.source_port` is taken and
+    /// `InvalidInput` if `options.metadata` does not fit in a frame.
+    #[tracing::instrument]
+    pub async fn connect_with(&self, port: u16, options: ConnectOptions) -> Result<MuxStream> {
+        self.connect_to(port, None, options).await
+    }
+
+    /// Connect to the listener bound to `name` on the remote end.
+    ///
+    /// The returned stream's `peer_port()` is 0, named connections are not
+    /// addressed by the peer's port.
+    #[tracing::instrument]
+    pub async fn connect_named(&self, name: &str) -> Result<MuxStream> {
+        self.connect_named_with(name, ConnectOptions::default())
+            .await
+    }
+
+    /// Connect to the listener bound to `name` on the remote end with
+    /// `options`.
+    ///
+    /// # Errors
+    /// Returns `ConnectionRefused` if the peer has nothing bound to `name`,
+    /// `Unsupported` if it does not support named services, and the errors
+    /// of `connect_with()`.
+    #[tracing::instrument]
+    pub async fn connect_named_with(
+        &self,
+        name: &str,
+        options: ConnectOptions,
+    ) -> Result<MuxStream> {
+        self.require(Features::NAMED_SERVICES).await?;
+        self.connect_to(0, Some(name), options).await
+    }
+
+    /// List the names currently bound on the remote end.
+    ///
+    /// # Errors
+    /// Returns `Unsupported` if the peer does not support named services,
+    /// `NotConnected` if the connection closes before it answers and
+    /// `InvalidData` if the names do not fit in a frame or it answers with
+    /// something else.
+    #[tracing::instrument]
+    pub async fn peer_names(&self) -> Result<Vec<String>> {
+        trace!("");
+        self.require(Features::NAMED_SERVICES).await?;
+        match self.inner.query(|id| Control::ListNames { id }).await? {
+            Control::Names { names, .. } => Ok(names),
+            other => {
+                debug!("{:?} does not answer ListNames", other);
+                Err(io::Error::from(io::ErrorKind::InvalidData))
+            }
+        }
+    }
+
+    /// List the ports the remote end has bound listeners to.
+    ///
+    /// # Errors
+    /// Returns `Unsupported` if the peer does not support listener
+    /// enumeration, `NotConnected` if the connection closes before it
+    /// answers and `InvalidData` if the ports do not fit in a frame or it
+    /// answers with something else.
+    #[tracing::instrument]
+    pub async fn remote_listeners(&self) -> Result<Vec<u16>> {
+        trace!("");
+        self.require(Features::REMOTE_LISTENERS).await?;
+        match self.inner.query(|id| Control::ListPorts { id }).await? {
+            Control::Ports { ports, .. } => Ok(ports),
+            other => {
+                debug!("{:?} does not answer ListPorts", other);
+                Err(io::Error::from(io::ErrorKind::InvalidData))
+            }
+        }
+    }
+
+    /// Return a `tokio::sync::broadcast::Receiver` of the remote end's
+    /// binds and unbinds, which closes when the multiplexor disconnects.
+    ///
+    /// Only changes made after subscribing are reported, call
+    /// `remote_listeners()` after subscribing to learn the ports already
+    /// bound, e.g. to wait for a service to come up:
+    ///
+    /// ```ignore
+    /// let mut events = mux.subscribe_remote_listeners().await?;
+    /// if !mux.remote_listeners().await?.contains(&22) {
+    ///     while events.recv().await? != ListenerEvent::Bound(22) {}
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    /// Returns `Unsupported` if the peer does not support bind
+    /// notifications and `NotConnected` if the multiplexor is disconnected.
+    #[tracing::instrument]
+    pub async fn subscribe_remote_listeners(&self) -> Result<broadcast::Receiver<ListenerEvent>> {
         trace!("");
-        if !self.inner.connected.load(Ordering::Relaxed) {
+        self.require(Features::REMOTE_LISTENERS).await?;
+        let receiver = self
+            .inner
+            .listener_events
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(broadcast::Sender::subscribe)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        if !self.inner.subscribed.swap(true, Ordering::SeqCst) {
+            if let Err(error) = self.inner.send_control(&Control::Subscribe).await {
+                // Let the next call try again
+                self.inner.subscribed.store(false, Ordering::SeqCst);
+                return Err(error);
+            }
+        }
+        Ok(receiver)
+    }
+
+    /// Wait for the HELLO exchange, fail with `Unsupported` unless both
+    /// ends support `feature`.
+    async fn require(&self, feature: Features) -> Result<()> {
+        let negotiated = self.inner.wait_negotiated().await?;
+        if negotiated.features.contains(feature) {
+            Ok(())
+        } else {
+            trace!("Peer does not support {:?}", feature);
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+    }
+
+    /// Wait for the HELLO exchange on behalf of a connect to `port`, giving
+    /// up at `deadline`.
+    async fn negotiated_by(&self, deadline: Option<Instant>, port: u16) -> Result<Negotiated> {
+        match deadline {
+            None => self.inner.wait_negotiated().await,
+            Some(deadline) => timeout_at(deadline, self.inner.wait_negotiated())
+                .await
+                .unwrap_or_else(|_| {
+                    debug!("Connect to port {} timed out negotiating", port);
+                    Err(io::Error::from(io::ErrorKind::TimedOut))
+                }),
+        }
+    }
+
+    async fn connect_to(
+        &self,
+        port: u16,
+        name: Option<&str>,
+        options: ConnectOptions,
+    ) -> Result<MuxStream> {
+        trace!("");
+        if !self.inner.connected.load(Ordering::Relaxed)
+            || self.inner.shutting_down.load(Ordering::Relaxed)
+        {
             trace!("Not connected, raise Error");
             return Err(io::Error::from(io::ErrorKind::ConnectionReset));
         }
-        let mut sport: u16 = 0;
-        while sport < 1024
-            || self
-                .inner
-                .port_connections
-                .read()
-                .await
-                .contains_key(&(sport, port))
+        let deadline = options.timeout.map(|duration| Instant::now() + duration);
+        let mut compression = options.compression.unwrap_or(self.inner.config.compression);
+        if compression != Compression::None {
+            let features = self.negotiated_by(deadline, port).await?.features;
+            if !features.contains(compression.feature()) {
+                debug!("Peer does not support {:?}, not compressing", compression);
+                compression = Compression::None;
+            }
+        }
+        // Window, then the metadata, name and compression options' tags and
+        // lengths
+        let name_len = name.map_or(0, |name| 3 + name.len());
+        let syn_len = frame::HEADER_SIZE + 4 + 3 + options.metadata.len() + name_len + 4;
+        // Metadata may not fit in the frame size the peer agrees to
+        let max_frame_size = if options.metadata.is_empty() {
+            self.inner.max_frame_size()
+        } else {
+            self.negotiated_by(deadline, port).await?.max_frame_size
+        };
+        if options.metadata.len() > usize::from(u16::MAX)
+            || name.is_some_and(|name| name.len() > usize::from(u16::MAX))
+            || syn_len > max_frame_size
         {
-            sport = rand::thread_rng().gen_range(1024u16..u16::MAX);
+            trace!("Syn of {} bytes too long", syn_len);
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
         }
+
+        let mut port_connections = self.inner.port_connections.write().await;
+        let sport = match options.source_port {
+            Some(sport) if port_connections.contains_key(&(sport, port)) => {
+                trace!("port_connections already contains ({}, {})", sport, port);
+                return Err(io::Error::from(io::ErrorKind::AddrInUse));
+            }
+            Some(sport) => sport,
+            None => {
+                let mut sport: u16 = 0;
+                while sport < 1024 || port_connections.contains_key(&(sport, port)) {
+                    sport = rand::thread_rng().gen_range(1024u16..u16::MAX);
+                }
+                sport
+            }
+        };
         trace!("sport = {}", sport);
 
-        let mux_socket = MuxSocket::new(self.inner.clone(), sport, port, false);
+        let mux_socket = MuxSocket::new(
+            self.inner.clone(),
+            sport,
+            port,
+            sport,
+            false,
+            options
+                .initial_window_size
+                .unwrap_or(self.inner.config.initial_window_size),
+            compression,
+        );
+        if options.priority != Priority::default() {
+            mux_socket.set_priority(options.priority);
+        }
         let mut rx = mux_socket.stream().await;
-        self.inner
-            .port_connections
-            .write()
-            .await
-            .insert((sport, port), mux_socket.clone());
-        mux_socket.start().await;
+        port_connections.insert((sport, port), mux_socket.clone());
+        drop(port_connections);
+        mux_socket.start(name, options.metadata).await;
 
-        rx.recv()
-            .await
-            .ok_or_else(|| io::Error::from(io::ErrorKind::Other))?
+        let accepted = async {
+            rx.recv()
+                .await
+                .ok_or_else(|| io::Error::from(io::ErrorKind::Other))?
+        };
+        match deadline {
+            None => accepted.await,
+            Some(deadline) => match timeout_at(deadline, accepted).await {
+                Ok(result) => result,
+                Err(_) => {
+                    debug!("Connect to port {} timed out", port);
+                    mux_socket
+                        .abort(ResetReason::Unspecified, io::ErrorKind::TimedOut)
+                        .await;
+                    self.inner
+                        .port_connections
+                        .write()
+                        .await
+                        .remove(&(sport, port));
+                    self.inner.priorities.lock().unwrap().remove(&(sport, port));
+                    Err(io::Error::from(io::ErrorKind::TimedOut))
+                }
+            },
+        }
     }
 
     /// Return a `tokio::sync::watch::Receiver` that will update to `false`
     /// when the inner stream closes.
+    ///
+    /// See `disconnect_reason()` for why it closed, including the close
+    /// code and reason sent by the peer.
     #[tracing::instrument]
     pub fn watch_connected(&self) -> watch::Receiver<bool> {
         trace!("");
         self.inner.watch_connected_send.subscribe()
     }
+
+    /// The protocol parameters agreed on with the peer, or `None` until its
+    /// HELLO has been received.
+    #[must_use]
+    pub fn negotiated(&self) -> Option<Negotiated> {
+        *self.inner.negotiated.borrow()
+    }
+
+    /// The static public key the peer authenticated with, for multiplexors
+    /// created with `new_encrypted()` or `from_io_encrypted()`.
+    #[cfg(feature = "noise")]
+    #[must_use]
+    pub fn peer_public_key(&self) -> Option<[u8; 32]> {
+        self.inner.peer_public_key.get().copied()
+    }
+
+    /// A snapshot of what the multiplexor and its open connections did so
+    /// far.
+    pub async fn stats(&self) -> MuxStats {
+        let queued_frames = self.inner.send.read().await.queued();
+        let connections = self
+            .inner
+            .port_connections
+            .read()
+            .await
+            .values()
+            .map(|socket| socket.stats())
+            .collect();
+        MuxStats {
+            queued_frames,
+            connections,
+            ..self.inner.counters.snapshot()
+        }
+    }
+
+    /// Why the inner stream closed, or `None` while still connected.
+    #[must_use]
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.inner.disconnect_reason.lock().unwrap().clone()
+    }
 }
 
 #[cfg(test)]
@@ -1,37 +1,63 @@
 use std::{
     fmt::{Debug, Formatter, Result as FmtResult},
     io,
+    pin::Pin,
     sync::Arc,
+    task::{Context, Poll},
 };
 
 extern crate async_channel;
-pub use tokio::io::DuplexStream;
+use futures_util::stream::Stream;
 use tracing::{debug, trace};
 
-use crate::{inner::WebSocketMultiplexorInner, Result};
+use crate::{inner::WebSocketMultiplexorInner, stream::MuxStream, Result};
 
-/// Listener struct returned by `WebSocketMultiplexor<T>::bind()`
+/// The peer of a connection returned by `MuxListener::accept()`, like the
+/// `SocketAddr` returned by `TcpListener::accept()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The port the peer connected from.
+    pub port: u16,
+    /// The metadata the peer sent along with its Syn, empty if there was
+    /// none. See `ConnectOptions::metadata`.
+    pub metadata: Vec<u8>,
+}
+
+/// A change to the peer's listeners, see
+/// `WebSocketMultiplexor::subscribe_remote_listeners()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ListenerEvent {
+    /// The peer bound a listener to this port.
+    Bound(u16),
+    /// The peer's listener on this port went away.
+    Unbound(u16),
+}
+
+/// Listener struct returned by `WebSocketMultiplexor::bind()`
+///
+/// The listener is also a `Stream` of accepted connections, which ends
+/// once the multiplexor disconnects.
 ///
 /// # Drop
 /// When the listener is dropped, it will free the port for reuse, but established
 /// connections will not be closed.
-pub struct MuxListener<Sink, Stream> {
-    inner: Arc<WebSocketMultiplexorInner<Sink, Stream>>,
+pub struct MuxListener {
+    inner: Arc<WebSocketMultiplexorInner>,
     port: u16,
-    recv: async_channel::Receiver<DuplexStream>,
+    recv: async_channel::Receiver<MuxStream>,
 }
 
-impl<Sink, Stream> MuxListener<Sink, Stream> {
+impl MuxListener {
     pub(crate) fn new(
-        inner: Arc<WebSocketMultiplexorInner<Sink, Stream>>,
+        inner: Arc<WebSocketMultiplexorInner>,
         port: u16,
-        recv: async_channel::Receiver<DuplexStream>,
+        recv: async_channel::Receiver<MuxStream>,
     ) -> Self {
         Self { inner, port, recv }
     }
 }
 
-impl<Sink, Stream> Debug for MuxListener<Sink, Stream> {
+impl Debug for MuxListener {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         f.debug_struct("MuxListener")
             .field("id", &self.inner.config.identifier)
@@ -40,22 +66,36 @@ impl<Sink, Stream> Debug for MuxListener<Sink, Stream> {
     }
 }
 
-impl<Sink, Stream> Drop for MuxListener<Sink, Stream> {
+impl Drop for MuxListener {
     fn drop(&mut self) {
         self.inner.may_close_listeners.send(self.port).ok();
         debug!("drop {:?}", self);
     }
 }
 
-impl<Sink, Stream> MuxListener<Sink, Stream> {
-    /// Accept a connection from the remote side
+impl MuxListener {
+    /// Accept a connection from the remote side, along with who connected
     #[tracing::instrument(level = "debug")]
-    pub async fn accept(&self) -> Result<DuplexStream> {
+    pub async fn accept(&self) -> Result<(MuxStream, PeerInfo)> {
         trace!("");
-        self.recv
-            .recv()
-            .await
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        let stream = self.recv.recv().await.map_err(io::Error::other)?;
+        let peer = PeerInfo {
+            port: stream.peer_port(),
+            metadata: stream.metadata().to_vec(),
+        };
+        Ok((stream, peer))
+    }
+
+    /// Get the number of connections waiting to be accepted. Further
+    /// connections are refused once it reaches `Config::accept_queue_len`
+    #[must_use]
+    pub fn backlog(&self) -> usize {
+        self.recv.len()
+    }
+
+    /// Get a `Stream` of accepted connections borrowing this listener
+    pub fn incoming(&mut self) -> Incoming<'_> {
+        Incoming { listener: self }
     }
 
     /// Get the port number of this listener
@@ -64,3 +104,27 @@ impl<Sink, Stream> MuxListener<Sink, Stream> {
         self.port
     }
 }
+
+impl Stream for MuxListener {
+    type Item = Result<MuxStream>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.recv)
+            .poll_next(cx)
+            .map(|stream| stream.map(Ok))
+    }
+}
+
+/// Stream of accepted connections returned by `MuxListener::incoming()`
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    listener: &'a mut MuxListener,
+}
+
+impl Stream for Incoming<'_> {
+    type Item = Result<MuxStream>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut *self.listener).poll_next(cx)
+    }
+}
@@ -0,0 +1,229 @@
+use std::{io, sync::Arc};
+
+use futures_util::{future, SinkExt, StreamExt};
+use snow::{params::NoiseParams, Builder, HandshakeState, StatelessTransportState};
+use tracing::{debug, trace};
+
+use crate::{
+    error::{DisconnectReason, FrameError},
+    frame::{Frame, VERSION},
+    transport::{self, ByteSink, ByteStream, FrameSink, FrameStream},
+};
+
+/// Handshake pattern without a pre-shared key. Both peers send their
+/// static key, so neither needs to know the other's beforehand.
+const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Handshake pattern mixing a pre-shared key into the last message.
+const PSK_PATTERN: &str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s";
+/// Largest Noise message, sealed frames are split into messages this big.
+const MAX_MESSAGE_LEN: usize = 65535;
+/// Size of the AEAD tag sealing each message.
+const TAG_LEN: usize = 16;
+/// Largest part of a frame sealed in one message.
+const MAX_PLAINTEXT_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+/// Keys for an encrypted multiplexor, see
+/// `WebSocketMultiplexor::new_encrypted()`.
+///
+/// Peers authenticate each other with static Curve25519 keys, and
+/// optionally a pre-shared key both must know.
+#[derive(Clone)]
+pub struct NoiseConfig {
+    initiator: bool,
+    private_key: [u8; 32],
+    remote_public_key: Option<[u8; 32]>,
+    psk: Option<[u8; 32]>,
+}
+
+impl std::fmt::Debug for NoiseConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseConfig")
+            .field("initiator", &self.initiator)
+            .field("remote_public_key", &self.remote_public_key)
+            .field("psk", &self.psk.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl NoiseConfig {
+    /// Keys for the side starting the handshake, usually the one that
+    /// opened the transport, with our static `private_key`.
+    #[must_use]
+    pub fn initiator(private_key: [u8; 32]) -> Self {
+        Self {
+            initiator: true,
+            private_key,
+            remote_public_key: None,
+            psk: None,
+        }
+    }
+
+    /// Keys for the side answering the handshake, with our static
+    /// `private_key`.
+    #[must_use]
+    pub fn responder(private_key: [u8; 32]) -> Self {
+        Self {
+            initiator: false,
+            ..Self::initiator(private_key)
+        }
+    }
+
+    /// Only accept a peer with this static public key
+    #[must_use]
+    pub fn with_remote_public_key(mut self, key: [u8; 32]) -> Self {
+        self.remote_public_key = Some(key);
+        self
+    }
+
+    /// Only accept a peer that knows `psk` too
+    #[must_use]
+    pub fn with_psk(mut self, psk: [u8; 32]) -> Self {
+        self.psk = Some(psk);
+        self
+    }
+
+    /// Generate a static key pair, returns the private and public keys.
+    ///
+    /// # Panics
+    /// Panics if no randomness is available.
+    #[must_use]
+    pub fn generate_keypair() -> ([u8; 32], [u8; 32]) {
+        let keypair = Builder::new(params(PATTERN))
+            .generate_keypair()
+            .expect("generating a key pair");
+        (
+            keypair.private.try_into().expect("32 byte private key"),
+            keypair.public.try_into().expect("32 byte public key"),
+        )
+    }
+
+    fn handshake_state(&self) -> Result<HandshakeState, snow::Error> {
+        let mut builder = Builder::new(params(if self.psk.is_some() {
+            PSK_PATTERN
+        } else {
+            PATTERN
+        }))
+        .prologue(PROLOGUE)
+        .local_private_key(&self.private_key);
+        if let Some(psk) = &self.psk {
+            builder = builder.psk(3, psk);
+        }
+        if self.initiator {
+            builder.build_initiator()
+        } else {
+            builder.build_responder()
+        }
+    }
+}
+
+/// Binds the handshake to this protocol and frame version.
+const PROLOGUE: &[u8] = &[b'w', b's', b'm', b'u', b'x', VERSION];
+
+fn params(pattern: &str) -> NoiseParams {
+    pattern.parse().expect("valid Noise pattern")
+}
+
+fn handshake_error(error: snow::Error) -> io::Error {
+    let kind = match error {
+        snow::Error::Decrypt => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::InvalidData,
+    };
+    io::Error::new(kind, error)
+}
+
+/// Size of a frame of `len` bytes once sealed.
+pub(crate) fn sealed_len(len: usize) -> usize {
+    len + len.div_ceil(MAX_PLAINTEXT_LEN).max(1) * TAG_LEN
+}
+
+/// Run the handshake over a transport of encoded frames, then seal the
+/// frames sent over it. Returns the frame transport and the peer's static
+/// public key.
+pub(crate) async fn handshake(
+    mut sink: ByteSink,
+    mut stream: ByteStream,
+    noise: &NoiseConfig,
+) -> io::Result<(FrameSink, FrameStream, [u8; 32])> {
+    let mut state = noise.handshake_state().map_err(handshake_error)?;
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state
+                .write_message(&[], &mut buf)
+                .map_err(handshake_error)?;
+            trace!("Send handshake message of {} bytes", len);
+            sink.send(buf[..len].to_vec()).await?;
+        } else {
+            let message = match stream.next().await {
+                Some(Ok(message)) => message,
+                Some(Err(reason)) => return Err(io::Error::new(reason.error_kind(), reason)),
+                None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            };
+            trace!("Received handshake message of {} bytes", message.len());
+            state
+                .read_message(&message, &mut buf)
+                .map_err(handshake_error)?;
+            // Give up before finishing the handshake with the wrong peer
+            if let (Some(expected), Some(key)) =
+                (noise.remote_public_key, state.get_remote_static())
+            {
+                if expected != key {
+                    debug!("Peer has an unexpected static key");
+                    return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+                }
+            }
+        }
+    }
+    let peer_key: [u8; 32] = state
+        .get_remote_static()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+    let transport = Arc::new(
+        state
+            .into_stateless_transport_mode()
+            .map_err(handshake_error)?,
+    );
+
+    let sealing = transport.clone();
+    let mut send_nonce = 0;
+    let sink = sink.with(move |frame: Frame| {
+        future::ready(seal(&sealing, &mut send_nonce, &Vec::from(frame)))
+    });
+    let mut recv_nonce = 0;
+    let stream = stream.map(move |sealed| {
+        sealed.and_then(|sealed| {
+            let data = open(&transport, &mut recv_nonce, &sealed)
+                .ok_or(DisconnectReason::Protocol(FrameError::Undecryptable))?;
+            transport::decode(data)
+        })
+    });
+    Ok((Box::pin(sink), Box::pin(stream), peer_key))
+}
+
+/// Seal an encoded frame, one Noise message per `MAX_PLAINTEXT_LEN` bytes.
+fn seal(transport: &StatelessTransportState, nonce: &mut u64, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut sealed = vec![0u8; sealed_len(data.len())];
+    let mut len = 0;
+    for chunk in data.chunks(MAX_PLAINTEXT_LEN) {
+        len += transport
+            .write_message(*nonce, chunk, &mut sealed[len..])
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        *nonce += 1;
+    }
+    sealed.truncate(len);
+    Ok(sealed)
+}
+
+/// Open a frame sealed by `seal()`, or `None` if it was tampered with.
+fn open(transport: &StatelessTransportState, nonce: &mut u64, sealed: &[u8]) -> Option<Vec<u8>> {
+    let mut data = vec![0u8; sealed.len()];
+    let mut len = 0;
+    for chunk in sealed.chunks(MAX_MESSAGE_LEN) {
+        len += transport
+            .read_message(*nonce, chunk, &mut data[len..])
+            .ok()?;
+        *nonce += 1;
+    }
+    data.truncate(len);
+    Some(data)
+}
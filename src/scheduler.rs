@@ -0,0 +1,271 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::{
+    self,
+    error::{SendError, TrySendError},
+};
+use tokio_util::sync::{PollSendError, PollSender};
+
+use crate::{frame::Frame, inner::PortPair};
+
+/// How the frames a stream sends are scheduled against other streams', see
+/// `ConnectOptions::priority` and `MuxStream::set_priority()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Priority {
+    /// Streams of a higher class always send first, those of lower classes
+    /// wait until they have nothing left to send.
+    pub class: u8,
+    /// Streams of the same class take turns, each sending up to this many
+    /// frames per turn.
+    pub weight: u16,
+}
+
+impl Priority {
+    /// A priority of `class` with `weight`
+    #[must_use]
+    pub fn new(class: u8, weight: u16) -> Self {
+        Self { class, weight }
+    }
+}
+
+impl Default for Priority {
+    /// Class 0, weight 1
+    fn default() -> Self {
+        Self::new(0, 1)
+    }
+}
+
+/// The writer's queue, frames go through a channel of `capacity` frames
+/// into a scheduler holding as many.
+pub(crate) fn queue(capacity: usize) -> (FrameSender, Scheduler) {
+    let (send, recv) = mpsc::channel(capacity);
+    let queued = Arc::new(AtomicUsize::new(0));
+    let sender = FrameSender {
+        send,
+        queued: queued.clone(),
+    };
+    (sender, Scheduler::new(recv, capacity, queued))
+}
+
+/// Queues frames for the writer, counting them until it takes them from
+/// the `Scheduler` and noting when they were queued.
+#[derive(Clone, Debug)]
+pub(crate) struct FrameSender {
+    send: mpsc::Sender<Queued>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl FrameSender {
+    /// Queue `frame`, waiting for room in the queue.
+    pub async fn send(&self, frame: Frame) -> Result<(), SendError<Frame>> {
+        match self.reserve().await {
+            Ok(permit) => {
+                permit.send(frame);
+                Ok(())
+            }
+            Err(_) => Err(SendError(frame)),
+        }
+    }
+
+    /// Wait for room in the queue.
+    pub async fn reserve(&self) -> Result<FramePermit<'_>, SendError<()>> {
+        let permit = self.send.reserve().await?;
+        Ok(FramePermit {
+            permit,
+            queued: &self.queued,
+        })
+    }
+
+    /// Reserve room in the queue without waiting.
+    pub fn try_reserve(&self) -> Result<FramePermit<'_>, TrySendError<()>> {
+        let permit = self.send.try_reserve()?;
+        Ok(FramePermit {
+            permit,
+            queued: &self.queued,
+        })
+    }
+
+    /// A sender for `poll_*` functions.
+    pub fn poll_sender(&self) -> PollFrameSender {
+        PollFrameSender {
+            send: PollSender::new(self.send.clone()),
+            queued: self.queued.clone(),
+        }
+    }
+
+    /// Frames queued and not yet taken by the writer.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+/// Room for one frame in the queue, see `FrameSender::reserve()`.
+pub(crate) struct FramePermit<'a> {
+    permit: mpsc::Permit<'a, Queued>,
+    queued: &'a AtomicUsize,
+}
+
+impl FramePermit<'_> {
+    pub fn send(self, frame: Frame) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.permit.send((frame, Instant::now()));
+    }
+}
+
+/// Queues frames for the writer from `poll_*` functions, see
+/// `FrameSender::poll_sender()`.
+pub(crate) struct PollFrameSender {
+    send: PollSender<Queued>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl PollFrameSender {
+    pub fn poll_reserve(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), PollSendError<Queued>>> {
+        self.send.poll_reserve(cx)
+    }
+
+    /// Queue `frame` in the room reserved by `poll_reserve()`.
+    pub fn send_item(&mut self, frame: Frame) -> Result<(), PollSendError<Queued>> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.send
+            .send_item((frame, Instant::now()))
+            .inspect_err(|_| {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+            })
+    }
+}
+
+/// A frame and when it was queued.
+pub(crate) type Queued = (Frame, Instant);
+
+/// Frames waiting to be sent for one stream, in order.
+struct StreamQueue {
+    frames: VecDeque<Queued>,
+    priority: Priority,
+    /// Frames sent in the current turn.
+    sent: u16,
+}
+
+/// Orders the frames queued by streams for the writer.
+///
+/// Mux-level frames go first, then streams by class, and streams of a class
+/// in weighted round robin. Each stream's frames keep their order. Frames
+/// are taken from the queue while fewer than `capacity` are held, so they
+/// wait here rather than behind another stream's, and producers block once
+/// both are full.
+pub(crate) struct Scheduler {
+    queue: mpsc::Receiver<Queued>,
+    /// Most frames held at once.
+    capacity: usize,
+    /// Frames held.
+    len: usize,
+    /// Frames held or in the queue, see `FrameSender::queued()`.
+    queued: Arc<AtomicUsize>,
+    /// Frames not sent by a stream, such as pings and HELLOs.
+    control: VecDeque<Queued>,
+    streams: HashMap<PortPair, StreamQueue>,
+    /// Streams with frames waiting, by class, the one whose turn it is
+    /// first.
+    classes: BTreeMap<u8, VecDeque<PortPair>>,
+}
+
+impl Scheduler {
+    fn new(queue: mpsc::Receiver<Queued>, capacity: usize, queued: Arc<AtomicUsize>) -> Self {
+        Self {
+            queue,
+            capacity: capacity.max(1),
+            len: 0,
+            queued,
+            control: VecDeque::new(),
+            streams: HashMap::new(),
+            classes: BTreeMap::new(),
+        }
+    }
+
+    /// Wait for the next frame to send, `priority` gives the priority of
+    /// the stream sending from a port pair. Returns the frame and how long
+    /// it waited, or `None` once the queue is closed.
+    pub async fn next(
+        &mut self,
+        priority: impl Fn(PortPair) -> Priority,
+    ) -> Option<(Frame, Duration)> {
+        if self.control.is_empty() && self.streams.is_empty() {
+            let frame = self.queue.recv().await?;
+            self.push(frame, &priority);
+        }
+        self.try_next(priority)
+    }
+
+    /// The next frame to send without waiting for the queue.
+    pub fn try_next(
+        &mut self,
+        priority: impl Fn(PortPair) -> Priority,
+    ) -> Option<(Frame, Duration)> {
+        while self.len < self.capacity {
+            let Ok(frame) = self.queue.try_recv() else {
+                break;
+            };
+            self.push(frame, &priority);
+        }
+        let (frame, queued) = self.pop()?;
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        Some((frame, queued.elapsed()))
+    }
+
+    fn push(&mut self, frame: Queued, priority: impl Fn(PortPair) -> Priority) {
+        let key = (frame.0.sport, frame.0.dport);
+        self.len += 1;
+        if key == (0, 0) {
+            self.control.push_back(frame);
+            return;
+        }
+        let stream = self.streams.entry(key).or_insert_with(|| {
+            let priority = priority(key);
+            self.classes
+                .entry(priority.class)
+                .or_default()
+                .push_back(key);
+            StreamQueue {
+                frames: VecDeque::new(),
+                priority,
+                sent: 0,
+            }
+        });
+        stream.frames.push_back(frame);
+    }
+
+    fn pop(&mut self) -> Option<Queued> {
+        if let Some(frame) = self.control.pop_front() {
+            self.len -= 1;
+            return Some(frame);
+        }
+        let mut class = self.classes.last_entry()?;
+        let key = *class.get().front()?;
+        let stream = self.streams.get_mut(&key)?;
+        let frame = stream.frames.pop_front()?;
+        self.len -= 1;
+        stream.sent += 1;
+        if stream.frames.is_empty() {
+            self.streams.remove(&key);
+            class.get_mut().pop_front();
+            if class.get().is_empty() {
+                class.remove();
+            }
+        } else if stream.sent >= stream.priority.weight {
+            stream.sent = 0;
+            class.get_mut().rotate_left(1);
+        }
+        Some(frame)
+    }
+}
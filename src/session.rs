@@ -0,0 +1,366 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt::{Debug, Formatter, Result as FmtResult},
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+};
+
+use futures_util::{
+    future, stream, Sink as FutureSink, SinkExt, Stream as FutureStream, StreamExt,
+};
+use tokio::sync::watch;
+use tracing::{debug, trace};
+
+use crate::{
+    config::Config,
+    error::DisconnectReason,
+    frame::{Flag, Frame},
+    hello::{Hello, SessionHello},
+    inner::WebSocketMultiplexorInner,
+    transport::{self, FrameSink, FrameStream, TransportMessage},
+    Result, WebSocketMultiplexor,
+};
+
+/// Acknowledge received frames after this many, so the peer can drop them
+/// from its retransmit buffer.
+const ACK_INTERVAL: u64 = 32;
+
+/// Produces a new transport for a resumable session.
+pub(crate) type Dialer = Box<
+    dyn FnMut() -> Pin<Box<dyn Future<Output = io::Result<(FrameSink, FrameStream)>> + Send>>
+        + Send,
+>;
+
+/// State of a multiplexor that survives transport failures, see
+/// `WebSocketMultiplexor::reconnecting()`.
+///
+/// Frames for which `Frame::is_resent()` holds are counted by both peers.
+/// Each peer keeps the frames it sent until the peer acknowledges them,
+/// and after reconnecting sends again those the peer did not receive.
+pub(crate) struct Session {
+    pub id: u64,
+    state: Mutex<SessionState>,
+    /// The current transport generation and whether its transport is up.
+    /// Bumped whenever a transport is attached or lost, tasks of older
+    /// generations exit.
+    pub generation: watch::Sender<(u64, bool)>,
+}
+
+struct SessionState {
+    /// Whether a transport was attached before, later ones resume.
+    started: bool,
+    /// Whether the current transport resumes the session.
+    resuming: bool,
+    /// Frames received from the peer.
+    received: u64,
+    /// Frames received since the last acknowledgement.
+    unacked: u64,
+    /// Frames sent to the peer.
+    sent: u64,
+    /// The last frames sent, not yet acknowledged by the peer.
+    retransmit: VecDeque<Frame>,
+    /// Why the last transport was lost.
+    lost_reason: DisconnectReason,
+}
+
+impl Debug for Session {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("Session")
+            .field("id", &self.id)
+            .field("generation", &*self.generation.borrow())
+            .finish()
+    }
+}
+
+impl Session {
+    pub fn new(id: u64) -> Self {
+        Self {
+            id,
+            state: Mutex::from(SessionState {
+                started: false,
+                resuming: false,
+                received: 0,
+                unacked: 0,
+                sent: 0,
+                retransmit: VecDeque::new(),
+                lost_reason: DisconnectReason::Transport(io::ErrorKind::NotConnected),
+            }),
+            generation: watch::channel((0, false)).0,
+        }
+    }
+
+    /// Start a new transport generation, superseding the current one.
+    /// Returns the generation and the session part of our HELLO.
+    pub fn attach(&self) -> (u64, SessionHello) {
+        let mut state = self.state.lock().unwrap();
+        let resume = state.started;
+        state.started = true;
+        state.resuming = resume;
+        state.unacked = 0;
+        let mut generation = 0;
+        self.generation.send_modify(|(current, live)| {
+            *current += 1;
+            *live = true;
+            generation = *current;
+        });
+        debug!("Session {} transport {} attached", self.id, generation);
+        (
+            generation,
+            SessionHello {
+                id: self.id,
+                resume,
+                received: state.received,
+            },
+        )
+    }
+
+    /// Give up the transport of `generation` after it failed with `reason`.
+    /// Returns false if it was already replaced or lost.
+    pub fn lose(&self, generation: u64, reason: DisconnectReason) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if *self.generation.borrow() != (generation, true) {
+            return false;
+        }
+        debug!(
+            "Session {} transport {} lost: {}",
+            self.id, generation, reason
+        );
+        state.lost_reason = reason;
+        self.generation.send_modify(|(current, live)| {
+            *current += 1;
+            *live = false;
+        });
+        true
+    }
+
+    /// Why the last transport was lost, or why the last attempt to
+    /// establish one failed.
+    pub fn lost_reason(&self) -> DisconnectReason {
+        self.state.lock().unwrap().lost_reason.clone()
+    }
+
+    pub fn set_lost_reason(&self, reason: DisconnectReason) {
+        self.state.lock().unwrap().lost_reason = reason;
+    }
+
+    /// Wait until `generation` is superseded.
+    pub async fn superseded(&self, generation: u64) {
+        let mut current = self.generation.subscribe();
+        current
+            .wait_for(|(current, _)| *current != generation)
+            .await
+            .ok();
+    }
+
+    /// Check the peer's HELLO on the transport of `generation`, returns how
+    /// many of our frames it received, or `None` if the transport was
+    /// superseded.
+    pub fn peer_hello(
+        &self,
+        generation: u64,
+        peer: Option<SessionHello>,
+    ) -> std::result::Result<Option<u64>, DisconnectReason> {
+        let state = self.state.lock().unwrap();
+        if self.generation.borrow().0 != generation {
+            return Ok(None);
+        }
+        match peer {
+            Some(peer) if peer.id == self.id && peer.resume == state.resuming => {
+                if peer.received > state.sent
+                    || peer.received + (state.retransmit.len() as u64) < state.sent
+                {
+                    debug!(
+                        "Peer received {} frames, {} sent, {} kept",
+                        peer.received,
+                        state.sent,
+                        state.retransmit.len()
+                    );
+                    return Err(DisconnectReason::SessionExpired);
+                }
+                Ok(Some(peer.received))
+            }
+            _ => {
+                debug!("Peer does not resume session {}: {:?}", self.id, peer);
+                Err(DisconnectReason::SessionExpired)
+            }
+        }
+    }
+
+    /// Count a frame received on the transport of `generation`. Returns
+    /// `None` if that transport was superseded, the frame must then be
+    /// dropped, or the count to acknowledge if one is due.
+    pub fn received(&self, generation: u64) -> Option<Option<u64>> {
+        let mut state = self.state.lock().unwrap();
+        if self.generation.borrow().0 != generation {
+            return None;
+        }
+        state.received += 1;
+        state.unacked += 1;
+        if state.unacked >= ACK_INTERVAL {
+            state.unacked = 0;
+            return Some(Some(state.received));
+        }
+        Some(None)
+    }
+
+    /// The peer received our first `count` frames.
+    pub fn acked(&self, count: u64) {
+        let mut state = self.state.lock().unwrap();
+        let kept = (state.sent - count.min(state.sent)) as usize;
+        while state.retransmit.len() > kept {
+            state.retransmit.pop_front();
+        }
+    }
+
+    /// Keep a frame about to be sent until the peer acknowledges it.
+    pub fn sending(&self, frame: &Frame) {
+        let mut state = self.state.lock().unwrap();
+        state.sent += 1;
+        state.retransmit.push_back(frame.clone());
+    }
+
+    /// The frames to send again to a peer that received our first
+    /// `received` frames, checked by `peer_hello()`.
+    pub fn unreceived(&self, received: u64) -> Vec<Frame> {
+        self.acked(received);
+        self.state
+            .lock()
+            .unwrap()
+            .retransmit
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Accepts transports from peers created with
+/// `WebSocketMultiplexor::reconnecting()`, resuming their sessions when
+/// they reconnect.
+///
+/// Sessions are forgotten once their multiplexor disconnects, which
+/// happens when no transport resumes them within `Config::resume_timeout`.
+pub struct Sessions {
+    config: Config,
+    sessions: Mutex<HashMap<u64, Weak<WebSocketMultiplexorInner>>>,
+}
+
+impl Debug for Sessions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("Sessions")
+            .field("id", &self.config.identifier)
+            .field("sessions", &self.sessions.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Sessions {
+    /// Accept sessions, creating their multiplexors with `config`.
+    #[must_use]
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            sessions: Mutex::from(HashMap::new()),
+        }
+    }
+
+    /// Accept a transport over a message-oriented transport, see
+    /// `WebSocketMultiplexor::new()`.
+    ///
+    /// Returns the new multiplexor if the transport starts a session, or
+    /// `None` if it resumed an existing one. Peers not created with
+    /// `WebSocketMultiplexor::reconnecting()` get a multiplexor that is not
+    /// resumable.
+    ///
+    /// # Errors
+    /// Returns `NotFound` if the transport tries to resume a session that
+    /// is gone, in which case the peer is told so, and `InvalidData` if the
+    /// peer does not start with a HELLO.
+    #[tracing::instrument(skip(sink, stream))]
+    pub async fn accept<Sink, Stream, M, E>(
+        &self,
+        sink: Sink,
+        stream: Stream,
+    ) -> Result<Option<WebSocketMultiplexor>>
+    where
+        M: TransportMessage + Send + 'static,
+        Sink: FutureSink<M> + Send + 'static,
+        Sink::Error: Error + Send + Sync + 'static,
+        Stream: FutureStream<Item = std::result::Result<M, E>> + Send + 'static,
+        E: Error + Send + Sync + 'static,
+    {
+        let (mut sink, mut stream) = transport::from_messages(sink, stream);
+        let first = match stream.next().await {
+            Some(Ok(frame)) => frame,
+            Some(Err(reason)) => return Err(io::Error::new(reason.error_kind(), reason)),
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        };
+        if !matches!(first.flag, Flag::Hello) {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+        let hello = Hello::decode(&first.data)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        trace!("Peer {:?}", hello);
+        // Let the multiplexor see the HELLO too
+        let stream: FrameStream = Box::pin(stream::once(future::ready(Ok(first))).chain(stream));
+
+        let Some(session) = hello.session else {
+            return Ok(Some(WebSocketMultiplexor::new_running(
+                sink,
+                stream,
+                self.config,
+                true,
+            )));
+        };
+        let resumed = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.retain(|_, inner| inner.upgrade().is_some_and(|inner| inner.is_connected()));
+            if !session.resume {
+                debug!("Starting session {}", session.id);
+                let mux = WebSocketMultiplexor::new_session(self.config, Session::new(session.id));
+                mux.inner.attach(sink, stream);
+                tokio::spawn(mux.inner.clone().supervise_session(None));
+                sessions.insert(session.id, Arc::downgrade(&mux.inner));
+                return Ok(Some(mux));
+            }
+            sessions.get(&session.id).and_then(Weak::upgrade)
+        };
+        if let Some(inner) = resumed {
+            debug!("Resuming session {}", session.id);
+            inner.attach(sink, stream);
+            return Ok(None);
+        }
+
+        debug!("Session {} is gone", session.id);
+        let gone = Hello::new(&self.config).with_session(SessionHello {
+            id: 0,
+            resume: false,
+            received: 0,
+        });
+        sink.send(Frame::new_hello(&gone)).await.ok();
+        sink.close().await.ok();
+        Err(io::Error::from(io::ErrorKind::NotFound))
+    }
+}
+
+/// Adapt a factory of message-oriented transports to a `Dialer`.
+pub(crate) fn dialer<F, Fut, Sink, Stream, M, E>(mut connect: F) -> Dialer
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = io::Result<(Sink, Stream)>> + Send + 'static,
+    M: TransportMessage + Send + 'static,
+    Sink: FutureSink<M> + Send + 'static,
+    Sink::Error: Error + Send + Sync + 'static,
+    Stream: FutureStream<Item = std::result::Result<M, E>> + Send + 'static,
+    E: Error + Send + Sync + 'static,
+{
+    Box::new(move || {
+        let connecting = connect();
+        Box::pin(async move {
+            let (sink, stream) = connecting.await?;
+            Ok(transport::from_messages(sink, stream))
+        })
+    })
+}
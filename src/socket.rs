@@ -2,23 +2,27 @@ use std::{
     fmt::{Debug, Formatter, Result as FmtResult},
     io,
     sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
     },
+    time::Instant,
 };
 
 extern crate async_channel;
 
-pub use tokio::io::DuplexStream;
-use tokio::{
-    io::{duplex, split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
-    sync::{mpsc, watch, RwLock},
-};
-use tracing::{debug, error, trace};
+use bytes::Bytes;
+use futures_util::task::AtomicWaker;
+use tokio::sync::{mpsc, RwLock};
+use tracing::{debug, error, trace, warn};
 
 use crate::{
-    frame::{Flag, Frame},
+    compression::Compression,
+    error::ResetReason,
+    frame::{Flag, Frame, SynOption},
     inner::WebSocketMultiplexorInner,
+    scheduler::Priority,
+    stats::{ConnectionState, ConnectionStats},
+    stream::MuxStream,
     Result,
 };
 
@@ -28,21 +32,54 @@ enum PortState {
     SynAck,
     Ack,
     Open,
+    /// We have sent Fin, the peer may still send data.
+    FinSent,
+    /// The peer has sent Fin, we may still send data.
+    FinReceived,
 }
 
-pub(crate) struct MuxSocket<Sink, Stream> {
-    inner: Arc<WebSocketMultiplexorInner<Sink, Stream>>,
+pub(crate) struct MuxSocket {
+    pub(crate) inner: Arc<WebSocketMultiplexorInner>,
     accepting: bool,
-    sport: u16,
-    dport: u16,
-    state: RwLock<PortState>,
-    seq: AtomicU32,
-    write_half: RwLock<Option<WriteHalf<DuplexStream>>>,
-    pub(crate) rst: watch::Sender<bool>,
-    pub(crate) external_stream_sender: RwLock<Option<mpsc::Sender<Result<DuplexStream>>>>,
+    pub(crate) sport: u16,
+    pub(crate) dport: u16,
+    /// The port the application sees, the listener's port for connections
+    /// accepted by name, which use port 0 on the wire.
+    pub(crate) local_port: u16,
+    state: Mutex<PortState>,
+    /// Sequence number of the next frame we send, locked while stamping
+    /// and queueing a frame so frames are queued in sequence.
+    seq: Mutex<u32>,
+    /// Sequence number of the next frame we expect from the peer.
+    recv_seq: AtomicU32,
+    /// Bytes we may still send before the peer has to grant more window.
+    send_window: AtomicU32,
+    /// Woken when the send window grows or the connection goes away.
+    send_waker: AtomicWaker,
+    /// Receive window advertised to the peer when the connection opened.
+    pub(crate) initial_window_size: u32,
+    /// Bytes the peer may still send before we have to grant more window.
+    recv_window: AtomicU32,
+    /// Codec compressing the data we send.
+    compression: Compression,
+    /// Opaque data sent along with the Syn.
+    metadata: OnceLock<Vec<u8>>,
+    /// Received data waiting to be read from the vended stream.
+    recv_queue: Mutex<Option<mpsc::UnboundedSender<Bytes>>>,
+    /// Why the connection went away, if it was not closed cleanly.
+    error: Mutex<Option<io::ErrorKind>>,
+    /// Why the peer reset the connection, if it did.
+    reset_reason: Mutex<Option<ResetReason>>,
+    /// When the connection was opened.
+    created: Instant,
+    /// When data or another frame last went through the connection.
+    last_activity: Mutex<Instant>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    pub(crate) external_stream_sender: RwLock<Option<mpsc::Sender<Result<MuxStream>>>>,
 }
 
-impl<Sink, Stream> Debug for MuxSocket<Sink, Stream> {
+impl Debug for MuxSocket {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         f.debug_struct("MuxSocket")
             .field("id", &self.inner.config.identifier)
@@ -52,267 +89,476 @@ impl<Sink, Stream> Debug for MuxSocket<Sink, Stream> {
     }
 }
 
-impl<Sink, Stream> Drop for MuxSocket<Sink, Stream> {
+impl Drop for MuxSocket {
     fn drop(&mut self) {
-        self.inner
-            .may_close_connections
-            .send((self.sport, self.dport))
-            .ok();
         debug!("drop {:?}", self);
+        self.inner.counters.stream_closed(self.created.elapsed());
     }
 }
 
-impl<Sink: 'static, Stream: 'static> MuxSocket<Sink, Stream> {
+impl MuxSocket {
     pub fn new(
-        inner: Arc<WebSocketMultiplexorInner<Sink, Stream>>,
+        inner: Arc<WebSocketMultiplexorInner>,
         sport: u16,
         dport: u16,
+        local_port: u16,
         accepting: bool,
+        initial_window_size: u32,
+        compression: Compression,
     ) -> Arc<Self> {
-        let (rst, _) = watch::channel(false);
         Arc::from(Self {
             inner,
             accepting,
             sport,
             dport,
-            state: RwLock::from(PortState::Closed),
-            seq: AtomicU32::new(0),
-            write_half: RwLock::from(None),
-            rst,
+            local_port,
+            state: Mutex::from(PortState::Closed),
+            seq: Mutex::from(0),
+            recv_seq: AtomicU32::new(0),
+            send_window: AtomicU32::new(0),
+            send_waker: AtomicWaker::new(),
+            initial_window_size,
+            recv_window: AtomicU32::new(initial_window_size),
+            compression,
+            metadata: OnceLock::new(),
+            recv_queue: Mutex::from(None),
+            error: Mutex::from(None),
+            reset_reason: Mutex::from(None),
+            created: Instant::now(),
+            last_activity: Mutex::from(Instant::now()),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
             external_stream_sender: RwLock::from(None),
         })
     }
 
-    pub async fn stream(self: &Arc<Self>) -> mpsc::Receiver<Result<DuplexStream>> {
+    pub async fn stream(self: &Arc<Self>) -> mpsc::Receiver<Result<MuxStream>> {
         trace!("");
         let (sender, receiver) = mpsc::channel(1);
         *self.external_stream_sender.write().await = Some(sender);
         receiver
     }
 
-    #[tracing::instrument(level = "trace")]
-    pub async fn start(self: &Arc<Self>) {
+    #[tracing::instrument(level = "trace", skip(metadata))]
+    pub async fn start(self: &Arc<Self>, name: Option<&str>, metadata: Vec<u8>) {
         trace!("");
-        if let Err(error) = self
-            .inner
-            .send
-            .write()
-            .await
-            .send(Frame::new_init(self.sport, self.dport, Flag::Syn))
-            .await
-        {
-            error!("Error {:?} sending Syn", error);
-        }
-        *self.state.write().await = PortState::Ack;
+        self.send_sequenced(|seq| {
+            let mut syn = self
+                .frame(Flag::Syn, seq)
+                .with_window(self.initial_window_size);
+            if let Some(name) = name {
+                syn = syn.with_option(SynOption::Name, name.as_bytes());
+            }
+            if !metadata.is_empty() {
+                syn = syn.with_option(SynOption::Metadata, &metadata);
+            }
+            if self.compression != Compression::None {
+                syn = syn.with_option(SynOption::Compression, &[self.compression.id()]);
+            }
+            syn
+        })
+        .await;
+        self.metadata.set(metadata).ok();
+        self.set_state(PortState::Ack);
     }
 
     #[tracing::instrument(level = "trace")]
-    async fn spawn_stream(self: &Arc<Self>) -> DuplexStream {
+    async fn spawn_stream(self: &Arc<Self>) -> MuxStream {
         trace!("");
-        let (s1, s2) = duplex(self.inner.config.max_frame_size);
+        let (recv_queue, recv_queue_recv) = mpsc::unbounded_channel();
+        *self.recv_queue.lock().unwrap() = Some(recv_queue);
+        let send = self.inner.send.read().await;
+        MuxStream::new(self.clone(), recv_queue_recv, &send)
+    }
+
+    fn state(&self) -> PortState {
+        *self.state.lock().unwrap()
+    }
 
-        let (read_half, write_half) = split(s2);
+    fn set_state(&self, state: PortState) {
+        *self.state.lock().unwrap() = state;
+    }
 
-        *self.write_half.write().await = Some(write_half);
+    /// The metadata sent along with the Syn, empty if there was none.
+    pub fn metadata(&self) -> &[u8] {
+        self.metadata.get().map_or(&[], Vec::as_slice)
+    }
 
-        tokio::spawn(self.clone().stream_read(read_half));
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state(), PortState::Closed)
+    }
 
-        s1
+    /// Whether the Syn, SynAck, Ack handshake is still in progress.
+    pub fn is_connecting(&self) -> bool {
+        matches!(self.state(), PortState::SynAck | PortState::Ack)
     }
 
-    #[tracing::instrument(level = "trace")]
-    async fn stream_read(self: Arc<Self>, read_half: ReadHalf<DuplexStream>) {
-        trace!("");
-        let mut rst = self.rst.subscribe();
-        let mut connected = self.inner.watch_connected_send.subscribe();
-        let mut read_half = read_half;
-        let mut buf: Vec<u8> = vec![0; self.inner.config.buf_size];
-        loop {
-            debug!("stream_read loop");
-            if *rst.borrow() {
-                trace!("Rst is true");
-                break;
+    /// Call `send` with the next sequence number, which must queue the
+    /// frame stamped with it without waiting.
+    pub fn sequenced<T>(&self, send: impl FnOnce(u32) -> T) -> T {
+        let mut seq = self.seq.lock().unwrap();
+        let sent = send(*seq);
+        *seq = seq.wrapping_add(1);
+        sent
+    }
+
+    /// Queue the frame built by `frame` from the next sequence number,
+    /// waiting for room in the send queue.
+    async fn send_sequenced(&self, frame: impl FnOnce(u32) -> Frame) {
+        match self.inner.send.write().await.reserve().await {
+            Ok(permit) => self.sequenced(|seq| permit.send(frame(seq))),
+            Err(error) => error!("Error {:?} sending frame", error),
+        }
+    }
+
+    /// What the connection did so far.
+    pub fn stats(&self) -> ConnectionStats {
+        let state = match self.state() {
+            PortState::SynAck | PortState::Ack => ConnectionState::Connecting,
+            PortState::Open => ConnectionState::Open,
+            PortState::FinSent => ConnectionState::FinSent,
+            PortState::FinReceived => ConnectionState::FinReceived,
+            PortState::Closed => ConnectionState::Closed,
+        };
+        ConnectionStats {
+            local_port: self.local_port,
+            peer_port: self.dport,
+            state,
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            age: self.created.elapsed(),
+            idle: self.last_activity.lock().unwrap().elapsed(),
+        }
+    }
+
+    /// Count `bytes` of data sent.
+    pub fn sent_data(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    pub fn set_priority(&self, priority: Priority) {
+        self.inner
+            .priorities
+            .lock()
+            .unwrap()
+            .insert((self.sport, self.dport), priority);
+    }
+
+    /// Compress data about to be sent, or `None` to send it as is.
+    pub fn compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < self.inner.config.compression_threshold {
+            return None;
+        }
+        self.compression.compress(data)
+    }
+
+    /// A data frame from this connection, `compressed` by `compress()`.
+    pub fn data_frame(&self, seq: u32, data: &[u8], compressed: Option<&[u8]>) -> Frame {
+        match compressed {
+            Some(compressed) => {
+                Frame::new_compressed(self.sport, self.dport, seq, self.compression, compressed)
             }
-            if !*connected.borrow() {
-                trace!("Connected is false");
-                break;
+            None => Frame::new_data(self.sport, self.dport, seq, data),
+        }
+    }
+
+    /// A frame without data from this connection.
+    pub fn frame(&self, flag: Flag, seq: u32) -> Frame {
+        Frame::new_no_data(self.sport, self.dport, flag, seq)
+    }
+
+    /// Whether we may still send data frames on this connection.
+    pub fn can_send(&self) -> io::Result<()> {
+        match self.state() {
+            PortState::Open | PortState::FinReceived => Ok(()),
+            PortState::FinSent => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+            _ => Err(self
+                .error()
+                .unwrap_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))),
+        }
+    }
+
+    /// The error reported to the application once the connection is gone.
+    pub fn error(&self) -> Option<io::Error> {
+        if let Some(reason) = *self.reset_reason.lock().unwrap() {
+            return Some(io::Error::from(reason));
+        }
+        self.error.lock().unwrap().map(io::Error::from)
+    }
+
+    pub fn send_window(&self) -> u32 {
+        self.send_window.load(Ordering::Acquire)
+    }
+
+    pub fn register_send_waker(&self, waker: &std::task::Waker) {
+        self.send_waker.register(waker);
+    }
+
+    pub fn consume_send_window(&self, bytes: u32) {
+        self.send_window.fetch_sub(bytes, Ordering::AcqRel);
+    }
+
+    fn grow_send_window(&self, increment: u32) {
+        let _ = self
+            .send_window
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |window| {
+                Some(window.saturating_add(increment))
+            });
+        self.send_waker.wake();
+    }
+
+    /// Account for `bytes` read by the application, returning them to the
+    /// receive window.
+    pub fn release_recv_window(&self, bytes: u32) {
+        self.recv_window.fetch_add(bytes, Ordering::AcqRel);
+    }
+
+    /// Move our write side to the closed state, returning whether to send
+    /// Fin, false if the write side is already closed.
+    pub fn shutdown_write(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            PortState::Open => {
+                *state = PortState::FinSent;
             }
-            let bytes = tokio::select! {
-                res = read_half.read(&mut buf) => {
-                    match res {
-                        Ok(bytes) => bytes,
-                        Err(error) => {
-                            error!("Error {:?} reading bytes from read_half", error);
-                            break;
-                        },
-                    }
-                }
-                _ = rst.changed() => {
-                    trace!("Rst changed");
-                    continue;
-                }
-                _ = connected.changed() => {
-                    trace!("Connected changed");
-                    continue;
-                }
-            };
-            trace!("bytes = {}", bytes);
-            if bytes == 0 {
-                trace!("bytes == 0; closed");
-                break;
+            PortState::FinReceived => {
+                drop(state);
+                self.close(None);
             }
-            if let Err(error) = self
-                .inner
-                .send
-                .write()
-                .await
-                .send(Frame::new_data(
-                    self.sport,
-                    self.dport,
-                    self.seq.fetch_add(1, Ordering::Relaxed),
-                    &buf[..bytes],
-                ))
-                .await
-            {
-                error!("Error {:?} sending data frame", error);
+            _ => {
+                trace!("Connection already closed, not sending Fin");
+                return false;
             }
         }
-        trace!("Drop write_half");
-        *self.write_half.write().await = None;
+        true
+    }
+
+    /// Send Fin after `shutdown_write()`.
+    pub async fn send_fin(&self) {
+        self.send_sequenced(|seq| self.frame(Flag::Fin, seq)).await;
+    }
+
+    /// Tear down the connection, it will not send or receive any more frames.
+    ///
+    /// `error` is reported to the application if the connection did not
+    /// close cleanly.
+    pub fn close(&self, error: Option<io::ErrorKind>) {
+        trace!("");
+        self.set_state(PortState::Closed);
+        if let Some(error) = error {
+            self.error.lock().unwrap().get_or_insert(error);
+        }
+        *self.recv_queue.lock().unwrap() = None;
+        self.send_waker.wake();
         self.inner
-            .port_connections
-            .write()
-            .await
-            .remove(&(self.sport, self.dport));
-
-        trace!("Send Fin");
-        if let Err(error) = self
-            .inner
-            .send
-            .write()
-            .await
-            .send(Frame::new_no_data(
-                self.sport,
-                self.dport,
-                Flag::Fin,
-                self.seq.fetch_add(1, Ordering::Relaxed),
-            ))
-            .await
-        {
-            error!("Error {:?} sending Fin", error);
+            .may_close_connections
+            .send((self.sport, self.dport))
+            .ok();
+    }
+
+    fn rst_frame(&self, reason: ResetReason, seq: u32) -> Frame {
+        self.frame(Flag::Rst, seq).with_reset_reason(reason)
+    }
+
+    /// Reset the connection without waiting for room in the send queue,
+    /// reporting `error` to the application.
+    pub async fn abort(&self, reason: ResetReason, error: io::ErrorKind) {
+        trace!("");
+        if !self.is_closed() {
+            match self.inner.send.read().await.try_reserve() {
+                Ok(permit) => self.sequenced(|seq| permit.send(self.rst_frame(reason, seq))),
+                Err(error) => warn!("Error {:?} sending Rst", error),
+            }
+        }
+        self.close(Some(error));
+    }
+
+    /// Reset the connection, reporting `reason` to the application.
+    pub async fn reset(&self, reason: ResetReason) {
+        self.send_rst(reason).await;
+        self.close(Some(reason.error_kind()));
+    }
+
+    /// Reset a connection still in its handshake, failing a pending
+    /// `connect` with `reason`.
+    pub async fn reset_connecting(&self, reason: ResetReason) {
+        if let Some(sender) = self.external_stream_sender.write().await.take() {
+            sender.try_send(Err(io::Error::from(reason))).ok();
+        }
+        self.reset(reason).await;
+    }
+
+    async fn send_rst(&self, reason: ResetReason) {
+        self.send_sequenced(|seq| self.rst_frame(reason, seq)).await;
+    }
+
+    /// Check that `frame` is the next one the peer sent, frames travel in
+    /// order so anything else is a bug or a misbehaving peer.
+    fn check_seq(&self, frame: &Frame) -> std::result::Result<(), ResetReason> {
+        let expected = self.recv_seq.load(Ordering::Acquire);
+        if frame.seq != expected {
+            return Err(ResetReason::OutOfOrder {
+                expected,
+                received: frame.seq,
+            });
         }
+        self.recv_seq
+            .store(expected.wrapping_add(1), Ordering::Release);
+        Ok(())
     }
 
     #[tracing::instrument(level = "trace")]
     pub async fn recv_frame(self: &Arc<Self>, frame: Frame) {
         trace!("");
-        let state: PortState = *self.state.read().await;
+        *self.last_activity.lock().unwrap() = Instant::now();
+        // Rsts may come from the peer's mux rather than the connection
+        if !matches!(frame.flag, Flag::Rst) {
+            if let Err(reason) = self.check_seq(&frame) {
+                warn!("{:?} received {:?}: {}, sending Rst", self, frame, reason);
+                // Tell the application what went wrong too
+                self.reset_reason.lock().unwrap().get_or_insert(reason);
+                self.reset(reason).await;
+                return;
+            }
+        }
+        let state: PortState = self.state();
         match frame.flag {
             Flag::Syn => {
                 if let PortState::Closed = state {
                     trace!("{:?} {:?}", frame.flag, state);
-                    if let Err(error) = self
-                        .inner
-                        .send
-                        .write()
-                        .await
-                        .send(Frame::new_reply(
-                            &frame,
-                            Flag::SynAck,
-                            self.seq.fetch_add(1, Ordering::Relaxed),
-                        ))
-                        .await
-                    {
-                        error!("Error {:?} sending SynAck", error);
+                    self.grow_send_window(
+                        frame
+                            .window()
+                            .unwrap_or(self.inner.config.initial_window_size),
+                    );
+                    if let Some(metadata) = frame.option(SynOption::Metadata) {
+                        self.metadata.set(metadata.to_vec()).ok();
                     }
-                    *self.state.write().await = PortState::SynAck;
+                    self.send_sequenced(|seq| {
+                        self.frame(Flag::SynAck, seq)
+                            .with_window(self.initial_window_size)
+                    })
+                    .await;
+                    self.set_state(PortState::SynAck);
                 }
             }
             Flag::SynAck | Flag::Ack => match state {
                 PortState::SynAck | PortState::Ack => {
                     trace!("{:?} {:?}", frame.flag, state);
-                    if let Err(error) = self
-                        .inner
-                        .send
-                        .write()
-                        .await
-                        .send(Frame::new_reply(
-                            &frame,
-                            Flag::Ack,
-                            self.seq.fetch_add(1, Ordering::Relaxed),
-                        ))
-                        .await
-                    {
-                        error!("Error {:?} sending Ack", error);
+                    if let Flag::SynAck = frame.flag {
+                        self.grow_send_window(
+                            frame
+                                .window()
+                                .unwrap_or(self.inner.config.initial_window_size),
+                        );
                     }
-                    *self.state.write().await = PortState::Open;
+                    self.send_sequenced(|seq| self.frame(Flag::Ack, seq)).await;
+                    self.set_state(PortState::Open);
                     if self.accepting {
-                        if let Some(sender) =
-                            self.inner.port_listeners.write().await.get(&frame.dport)
-                        {
-                            let stream = self.spawn_stream().await;
-                            if let Err(error) = sender.send(stream).await {
-                                error!("Error {:?} sending DuplexStream to acceptor", error);
-                            }
-                        }
+                        let sender = self
+                            .inner
+                            .port_listeners
+                            .read()
+                            .await
+                            .get(&self.local_port)
+                            .cloned();
+                        let stream = self.spawn_stream().await;
+                        // Never wait for the acceptor, that would stall every stream
+                        let reason = match sender {
+                            Some(sender) => match sender.try_send(stream) {
+                                Ok(()) => return,
+                                Err(async_channel::TrySendError::Full(_)) => ResetReason::QueueFull,
+                                Err(async_channel::TrySendError::Closed(_)) => ResetReason::Refused,
+                            },
+                            None => ResetReason::Refused,
+                        };
+                        debug!("Cannot queue {:?} for accept, sending Rst", self);
+                        self.reset(reason).await;
                     } else if let Some(sender) = self.external_stream_sender.write().await.as_ref()
                     {
                         let stream = self.spawn_stream().await;
                         if let Err(error) = sender.send(Ok(stream)).await {
-                            error!("Error {:?} sending DuplexStream to connector", error);
+                            error!("Error {:?} sending MuxStream to connector", error);
                         }
                     }
                 }
                 _ => {}
             },
             Flag::Unset => {
-                if let PortState::Open = state {
+                if let PortState::Open | PortState::FinSent = state {
                     trace!("{:?} {:?}", frame.flag, state);
-                    if let Some(write_half) = self.write_half.write().await.as_mut() {
-                        if let Err(error) = write_half.write_all(&frame.data).await {
-                            error!("Error {:?} writing data to write_half", error);
-                        }
+                    let len = frame.data.len() as u32;
+                    self.bytes_in.fetch_add(u64::from(len), Ordering::Relaxed);
+                    if self
+                        .recv_window
+                        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |window| {
+                            window.checked_sub(len)
+                        })
+                        .is_err()
+                    {
+                        warn!("Peer exceeded receive window, sending Rst");
+                        self.reset(ResetReason::ProtocolError).await;
+                        return;
+                    }
+                    let queued = match self.recv_queue.lock().unwrap().as_ref() {
+                        Some(recv_queue) => recv_queue.send(Bytes::from(frame.data)).is_ok(),
+                        None => false,
+                    };
+                    if !queued {
+                        // The vended stream was dropped entirely, nobody will
+                        // ever read this data.
+                        trace!("Reader is gone, sending Rst");
+                        self.send_rst(ResetReason::Unspecified).await;
+                        self.close(None);
                     }
                 }
             }
-            Flag::Fin => {
-                if let PortState::Open = state {
+            Flag::WindowUpdate => {
+                if let PortState::Open | PortState::FinReceived = state {
                     trace!("{:?} {:?}", frame.flag, state);
-                    if let Err(error) = self
-                        .inner
-                        .send
-                        .write()
-                        .await
-                        .send(Frame::new_reply(
-                            &frame,
-                            Flag::Fin,
-                            self.seq.fetch_add(1, Ordering::Relaxed),
-                        ))
-                        .await
-                    {
-                        error!("Error {:?} sending Fin", error);
+                    if let Some(increment) = frame.window() {
+                        self.grow_send_window(increment);
                     }
-                    *self.state.write().await = PortState::Closed;
-                    *self.write_half.write().await = None;
-                    let _ = self.rst.send(true);
                 }
             }
+            Flag::Fin => {
+                let mut state = self.state.lock().unwrap();
+                trace!("{:?} {:?}", frame.flag, *state);
+                match *state {
+                    PortState::Open => {
+                        // Signal EOF to the reader once queued data is delivered
+                        *state = PortState::FinReceived;
+                        *self.recv_queue.lock().unwrap() = None;
+                    }
+                    PortState::FinSent => {
+                        drop(state);
+                        self.close(None);
+                    }
+                    _ => {}
+                }
+            }
+            // Handled by the reader, which decompresses data before handing
+            // it to us
+            Flag::Ping
+            | Flag::Pong
+            | Flag::Hello
+            | Flag::Control
+            | Flag::SessionAck
+            | Flag::Compressed => {}
             Flag::Rst => {
+                let reason = frame.reset_reason();
+                trace!("{:?} {:?} {:?}", frame.flag, state, reason);
                 if matches!(state, PortState::Closed | PortState::Ack) {
-                    trace!("{:?} {:?}", frame.flag, state);
                     if let Some(stream_sender) = self.external_stream_sender.write().await.as_ref()
                     {
-                        if let Err(error) = stream_sender
-                            .send(Err(io::Error::from(io::ErrorKind::AddrNotAvailable)))
-                            .await
-                        {
+                        if let Err(error) = stream_sender.send(Err(io::Error::from(reason))).await {
                             error!("Error {:?} sending Error to connection", error);
                         }
                     }
                 }
-                *self.state.write().await = PortState::Closed;
-                *self.write_half.write().await = None;
-                let _ = self.rst.send(true);
+                self.reset_reason.lock().unwrap().get_or_insert(reason);
+                self.close(Some(reason.error_kind()));
             }
         }
     }
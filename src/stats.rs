@@ -0,0 +1,237 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::frame::{Flag, Frame};
+
+/// What a multiplexor did so far, see `WebSocketMultiplexor::stats()`.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct MuxStats {
+    /// Frames received from the peer.
+    pub frames_in: u64,
+    /// Frames sent to the peer.
+    pub frames_out: u64,
+    /// Bytes of the frames received, headers included.
+    pub bytes_in: u64,
+    /// Bytes of the frames sent, headers included.
+    pub bytes_out: u64,
+    /// Rst frames sent.
+    pub rsts_sent: u64,
+    /// Rst frames received.
+    pub rsts_received: u64,
+    /// Syns refused, because nothing was bound to their port or the
+    /// listener's accept queue was full.
+    pub syns_refused: u64,
+    /// Frames received that were malformed or violated the protocol.
+    pub decode_errors: u64,
+    /// Frames queued by streams, not yet taken by the writer.
+    pub queued_frames: usize,
+    /// The open connections.
+    pub connections: Vec<ConnectionStats>,
+}
+
+/// What a connection did so far, see `MuxStats::connections`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ConnectionStats {
+    /// The port the application sees, see `MuxStream::local_port()`.
+    pub local_port: u16,
+    /// The peer's port, see `MuxStream::peer_port()`.
+    pub peer_port: u16,
+    /// Where the connection is in its lifecycle.
+    pub state: ConnectionState,
+    /// Bytes of data received.
+    pub bytes_in: u64,
+    /// Bytes of data sent.
+    pub bytes_out: u64,
+    /// Time since the connection was opened.
+    pub age: Duration,
+    /// Time since data or another frame last went through the connection.
+    pub idle: Duration,
+}
+
+/// Where a connection is in its lifecycle, see `ConnectionStats::state`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConnectionState {
+    /// Waiting for the handshake to complete.
+    Connecting,
+    /// Both sides may send.
+    Open,
+    /// We shut down our write side, the peer may still send.
+    FinSent,
+    /// The peer shut down its write side, we may still send.
+    FinReceived,
+    /// Both sides are done, or the connection was reset.
+    Closed,
+}
+
+/// Counters of a multiplexor, see `MuxStats`.
+#[derive(Debug)]
+pub(crate) struct Counters {
+    frames_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    rsts_sent: AtomicU64,
+    rsts_received: AtomicU64,
+    syns_refused: AtomicU64,
+    decode_errors: AtomicU64,
+    #[cfg(feature = "metrics")]
+    metrics: Metrics,
+}
+
+impl Counters {
+    /// Counters of the multiplexor named `identifier`, see
+    /// `Config::identifier`.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub fn new(identifier: &'static str) -> Self {
+        Self {
+            frames_in: AtomicU64::new(0),
+            frames_out: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            rsts_sent: AtomicU64::new(0),
+            rsts_received: AtomicU64::new(0),
+            syns_refused: AtomicU64::new(0),
+            decode_errors: AtomicU64::new(0),
+            #[cfg(feature = "metrics")]
+            metrics: Metrics::new(identifier),
+        }
+    }
+
+    pub fn received(&self, frame: &Frame) {
+        let len = frame.encoded_len() as u64;
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(len, Ordering::Relaxed);
+        if matches!(frame.flag, Flag::Rst) {
+            self.rsts_received.fetch_add(1, Ordering::Relaxed);
+        }
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics.frames_in.increment(1);
+            self.metrics.bytes_in.increment(len);
+            self.metrics.frame_size_in.record(len as f64);
+            if matches!(frame.flag, Flag::Rst) {
+                self.metrics.rsts_received.increment(1);
+            }
+        }
+    }
+
+    pub fn sent(&self, frame: &Frame) {
+        let len = frame.encoded_len() as u64;
+        self.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(len, Ordering::Relaxed);
+        if matches!(frame.flag, Flag::Rst) {
+            self.rsts_sent.fetch_add(1, Ordering::Relaxed);
+        }
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics.frames_out.increment(1);
+            self.metrics.bytes_out.increment(len);
+            self.metrics.frame_size_out.record(len as f64);
+            if matches!(frame.flag, Flag::Rst) {
+                self.metrics.rsts_sent.increment(1);
+            }
+        }
+    }
+
+    pub fn syn_refused(&self) {
+        self.syns_refused.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.syns_refused.increment(1);
+    }
+
+    pub fn decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.decode_errors.increment(1);
+    }
+
+    /// The peer's Hello arrived `latency` after the transport was attached.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub fn handshake(&self, latency: Duration) {
+        #[cfg(feature = "metrics")]
+        self.metrics.handshake.record(latency);
+    }
+
+    /// A frame waited `wait` in the writer's queue before being sent.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub fn queue_wait(&self, wait: Duration) {
+        #[cfg(feature = "metrics")]
+        self.metrics.queue_wait.record(wait);
+    }
+
+    /// A connection was freed `lifetime` after it was created.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub fn stream_closed(&self, lifetime: Duration) {
+        #[cfg(feature = "metrics")]
+        self.metrics.stream_lifetime.record(lifetime);
+    }
+
+    /// A snapshot of the counters, without queue and connections.
+    pub fn snapshot(&self) -> MuxStats {
+        MuxStats {
+            frames_in: self.frames_in.load(Ordering::Relaxed),
+            frames_out: self.frames_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            rsts_sent: self.rsts_sent.load(Ordering::Relaxed),
+            rsts_received: self.rsts_received.load(Ordering::Relaxed),
+            syns_refused: self.syns_refused.load(Ordering::Relaxed),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            ..MuxStats::default()
+        }
+    }
+}
+
+/// Handles of the metrics published through the `metrics` facade, labelled
+/// with the multiplexor's identifier.
+///
+/// The handles are registered when the multiplexor is created, so the
+/// recorder has to be installed before that.
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+struct Metrics {
+    frames_in: metrics::Counter,
+    frames_out: metrics::Counter,
+    bytes_in: metrics::Counter,
+    bytes_out: metrics::Counter,
+    rsts_sent: metrics::Counter,
+    rsts_received: metrics::Counter,
+    syns_refused: metrics::Counter,
+    decode_errors: metrics::Counter,
+    frame_size_in: metrics::Histogram,
+    frame_size_out: metrics::Histogram,
+    handshake: metrics::Histogram,
+    queue_wait: metrics::Histogram,
+    stream_lifetime: metrics::Histogram,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn new(identifier: &'static str) -> Self {
+        use metrics::{counter, histogram};
+        Self {
+            frames_in: counter!("wsmux_frames_received_total", "mux" => identifier),
+            frames_out: counter!("wsmux_frames_sent_total", "mux" => identifier),
+            bytes_in: counter!("wsmux_bytes_received_total", "mux" => identifier),
+            bytes_out: counter!("wsmux_bytes_sent_total", "mux" => identifier),
+            rsts_sent: counter!("wsmux_rsts_sent_total", "mux" => identifier),
+            rsts_received: counter!("wsmux_rsts_received_total", "mux" => identifier),
+            syns_refused: counter!("wsmux_syns_refused_total", "mux" => identifier),
+            decode_errors: counter!("wsmux_decode_errors_total", "mux" => identifier),
+            frame_size_in: histogram!(
+                "wsmux_frame_size_bytes", "mux" => identifier, "direction" => "received"
+            ),
+            frame_size_out: histogram!(
+                "wsmux_frame_size_bytes", "mux" => identifier, "direction" => "sent"
+            ),
+            handshake: histogram!("wsmux_handshake_seconds", "mux" => identifier),
+            queue_wait: histogram!("wsmux_queue_wait_seconds", "mux" => identifier),
+            stream_lifetime: histogram!("wsmux_stream_lifetime_seconds", "mux" => identifier),
+        }
+    }
+}
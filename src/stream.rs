@@ -0,0 +1,345 @@
+use std::{
+    fmt::{Debug, Formatter, Result as FmtResult},
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc,
+};
+use tracing::{debug, trace};
+
+use crate::{
+    error::ResetReason,
+    frame::{Flag, Frame},
+    scheduler::{FrameSender, PollFrameSender, Priority},
+    socket::MuxSocket,
+};
+
+/// A stream between a local and a remote port, returned by
+/// `WebSocketMultiplexor::connect()` and `MuxListener::accept()`.
+///
+/// Shutting down the write side sends Fin to the peer, the stream can still
+/// be read from until the peer shuts down its own write side.
+///
+/// # Drop
+/// When the stream is dropped, its write side is shut down. Any data
+/// received afterwards resets the connection.
+pub struct MuxStream {
+    read: OwnedReadHalf,
+    write: OwnedWriteHalf,
+}
+
+/// The read half of a `MuxStream`, created by `MuxStream::into_split()`.
+pub struct OwnedReadHalf {
+    socket: Arc<MuxSocket>,
+    recv: mpsc::UnboundedReceiver<Bytes>,
+    send: PollFrameSender,
+    /// Received data not yet read by the application.
+    buffered: Bytes,
+    /// Bytes read by the application but not yet granted back to the peer.
+    consumed: u32,
+}
+
+/// The write half of a `MuxStream`, created by `MuxStream::into_split()`.
+///
+/// # Drop
+/// When the write half is dropped, it sends Fin to the peer.
+pub struct OwnedWriteHalf {
+    socket: Arc<MuxSocket>,
+    send: PollFrameSender,
+    shutdown: bool,
+}
+
+impl MuxStream {
+    pub(crate) fn new(
+        socket: Arc<MuxSocket>,
+        recv: mpsc::UnboundedReceiver<Bytes>,
+        send: &FrameSender,
+    ) -> Self {
+        Self {
+            read: OwnedReadHalf {
+                socket: socket.clone(),
+                recv,
+                send: send.poll_sender(),
+                buffered: Bytes::new(),
+                consumed: 0,
+            },
+            write: OwnedWriteHalf {
+                socket,
+                send: send.poll_sender(),
+                shutdown: false,
+            },
+        }
+    }
+
+    /// Get the local port of this stream
+    #[must_use]
+    pub fn local_port(&self) -> u16 {
+        self.read.local_port()
+    }
+
+    /// Get the remote port of this stream
+    #[must_use]
+    pub fn peer_port(&self) -> u16 {
+        self.read.peer_port()
+    }
+
+    /// Get the metadata the connecting side sent along with its Syn, empty
+    /// if there was none
+    #[must_use]
+    pub fn metadata(&self) -> &[u8] {
+        self.read.socket.metadata()
+    }
+
+    /// Reset the stream, the peer's reads and writes fail with
+    /// `ConnectionAborted` carrying `ResetReason::Abort(code)`.
+    pub async fn abort(self, code: u32) {
+        self.write.abort(code).await;
+    }
+
+    /// Schedule what the stream sends with `priority`, see
+    /// `OwnedWriteHalf::set_priority()`.
+    pub fn set_priority(&self, priority: Priority) {
+        self.write.set_priority(priority);
+    }
+
+    /// Split the stream into a read half and a write half, which can be
+    /// moved to different tasks.
+    #[must_use]
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        (self.read, self.write)
+    }
+}
+
+impl Debug for MuxStream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("MuxStream")
+            .field("id", &self.read.socket.inner.config.identifier)
+            .field("local_port", &self.local_port())
+            .field("peer_port", &self.peer_port())
+            .finish()
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.read).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.write).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.write).poll_shutdown(cx)
+    }
+}
+
+impl OwnedReadHalf {
+    /// Get the local port of this stream
+    #[must_use]
+    pub fn local_port(&self) -> u16 {
+        self.socket.local_port
+    }
+
+    /// Get the remote port of this stream
+    #[must_use]
+    pub fn peer_port(&self) -> u16 {
+        self.socket.dport
+    }
+
+    /// Grant the peer the window consumed by the application, if it is
+    /// worth a frame or the peer might be waiting on it.
+    fn poll_window_update(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.consumed == 0 || self.socket.is_closed() {
+            return Poll::Ready(());
+        }
+        let drained = self.buffered.is_empty() && self.recv.is_empty();
+        if !drained && self.consumed < self.socket.initial_window_size / 2 {
+            return Poll::Ready(());
+        }
+        if ready!(self.send.poll_reserve(cx)).is_ok() {
+            trace!("Send WindowUpdate {}", self.consumed);
+            let (socket, send) = (&self.socket, &mut self.send);
+            socket.sequenced(|seq| {
+                let frame =
+                    Frame::new_window_update(socket.sport, socket.dport, seq, self.consumed);
+                send.send_item(frame).ok();
+            });
+        }
+        self.consumed = 0;
+        Poll::Ready(())
+    }
+}
+
+impl Debug for OwnedReadHalf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("OwnedReadHalf")
+            .field("id", &self.socket.inner.config.identifier)
+            .field("local_port", &self.local_port())
+            .field("peer_port", &self.peer_port())
+            .finish()
+    }
+}
+
+impl AsyncRead for OwnedReadHalf {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.poll_window_update(cx));
+            if !self.buffered.is_empty() {
+                let bytes = self.buffered.len().min(buf.remaining());
+                buf.put_slice(&self.buffered[..bytes]);
+                self.buffered.advance(bytes);
+                self.socket.release_recv_window(bytes as u32);
+                self.consumed = self.consumed.saturating_add(bytes as u32);
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(self.recv.poll_recv(cx)) {
+                Some(data) => self.buffered = data,
+                None => {
+                    return Poll::Ready(match self.socket.error() {
+                        Some(error) => Err(error),
+                        None => Ok(()),
+                    })
+                }
+            }
+        }
+    }
+}
+
+impl OwnedWriteHalf {
+    /// Get the local port of this stream
+    #[must_use]
+    pub fn local_port(&self) -> u16 {
+        self.socket.local_port
+    }
+
+    /// Get the remote port of this stream
+    #[must_use]
+    pub fn peer_port(&self) -> u16 {
+        self.socket.dport
+    }
+
+    /// Reset the stream, the peer's reads and writes fail with
+    /// `ConnectionAborted` carrying `ResetReason::Abort(code)`.
+    pub async fn abort(mut self, code: u32) {
+        self.shutdown = true;
+        self.socket.reset(ResetReason::Abort(code)).await;
+    }
+
+    /// Schedule what the stream sends with `priority` against other
+    /// streams. Frames already waiting to be sent keep their priority.
+    pub fn set_priority(&self, priority: Priority) {
+        self.socket.set_priority(priority);
+    }
+}
+
+impl Debug for OwnedWriteHalf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("OwnedWriteHalf")
+            .field("id", &self.socket.inner.config.identifier)
+            .field("local_port", &self.local_port())
+            .field("peer_port", &self.peer_port())
+            .finish()
+    }
+}
+
+impl Drop for OwnedWriteHalf {
+    fn drop(&mut self) {
+        if !self.shutdown {
+            // Let the mux maintenance task send our Fin
+            self.socket
+                .inner
+                .may_close_connections
+                .send((self.socket.sport, self.socket.dport))
+                .ok();
+        }
+        debug!("drop {:?}", self);
+    }
+}
+
+impl AsyncWrite for OwnedWriteHalf {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.shutdown {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+        self.socket.can_send()?;
+        self.socket.register_send_waker(cx.waker());
+        let window = self.socket.send_window();
+        if window == 0 {
+            trace!("Waiting for send window");
+            return Poll::Pending;
+        }
+        if ready!(self.send.poll_reserve(cx)).is_err() {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+        // The connection may have gone away while we waited
+        self.socket.can_send()?;
+        let max_data_size = self.socket.inner.max_data_size();
+        if max_data_size == 0 {
+            // Not even a byte of data fits in a frame
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::InvalidInput)));
+        }
+        let bytes = buf.len().min(window as usize).min(max_data_size);
+        let this = &mut *self;
+        let compressed = this.socket.compress(&buf[..bytes]);
+        let sent = this.socket.sequenced(|seq| {
+            let frame = this
+                .socket
+                .data_frame(seq, &buf[..bytes], compressed.as_deref());
+            this.send.send_item(frame)
+        });
+        if sent.is_err() {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+        self.socket.consume_send_window(bytes as u32);
+        self.socket.sent_data(bytes);
+        Poll::Ready(Ok(bytes))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.shutdown {
+            return Poll::Ready(Ok(()));
+        }
+        if ready!(self.send.poll_reserve(cx)).is_ok() && self.socket.shutdown_write() {
+            trace!("Send Fin");
+            let this = &mut *self;
+            this.socket.sequenced(|seq| {
+                this.send.send_item(this.socket.frame(Flag::Fin, seq)).ok();
+            });
+        }
+        self.shutdown = true;
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,31 +1,36 @@
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicUsize, Ordering},
     Arc,
 };
 
-use futures_util::stream::StreamExt;
+use futures_util::{sink::SinkExt, stream::StreamExt};
 use tokio::{
     io::{duplex, AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
-    sync::mpsc,
-    time::{sleep, Duration},
+    sync::{mpsc, watch},
+    time::{sleep, timeout, Duration, Instant},
 };
 use tokio_tungstenite::WebSocketStream;
 use tracing::{info, trace};
 use tracing_subscriber::filter::EnvFilter;
-use tungstenite::protocol::Role;
+use tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame, Role},
+    Message,
+};
 
-use crate::{Config, WebSocketMultiplexor};
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+use crate::Compression;
+#[cfg(feature = "noise")]
+use crate::NoiseConfig;
+use crate::{
+    frame::{Flag, Frame},
+    scheduler, Config, ConnectOptions, ConnectionState, DisconnectReason, Features, FrameError,
+    ListenerEvent, PeerInfo, Priority, ResetReason, Sessions, WebSocketMultiplexor,
+};
 
 #[ctor::ctor]
 fn init_tests() {
-    let mut filter = {
-        if let Ok(filter) = EnvFilter::try_from_default_env() {
-            filter
-        } else {
-            EnvFilter::default()
-        }
-    };
+    let mut filter = EnvFilter::try_from_default_env().unwrap_or_default();
     filter = filter.add_directive("tokio_stream_multiplexor=trace".parse().unwrap());
 
     tracing_subscriber::fmt::Subscriber::builder()
@@ -71,7 +76,7 @@ async fn connect_listen_succeeds() {
         sleep(Duration::from_millis(50)).await;
     });
 
-    assert!(matches!(sm_a.connect(22).await, Ok(_)));
+    assert!(sm_a.connect(22).await.is_ok());
 }
 
 #[tokio::test]
@@ -94,9 +99,92 @@ async fn dropped_connection_rsts() {
     });
 
     let res = sm_a.connect(22).await;
-    assert!(matches!(res, Ok(_)));
-    let res = res.unwrap().write_all(&[0u8; 1024]).await;
-    assert!(matches!(res, Err(_)));
+    assert!(res.is_ok());
+    let mut stream = res.unwrap();
+    // The first writes may land before the peer's Rst arrives, like TCP
+    let mut res = Ok(());
+    for _ in 0..10 {
+        res = stream.write_all(&[0u8; 1024]).await;
+        if res.is_err() {
+            break;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn half_closed_stream_still_reads() {
+    let (a, b) = duplex(10);
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+    let (b_sink, b_stream) = b_ws.split();
+
+    let sm_a =
+        WebSocketMultiplexor::new(a_sink, a_stream, Config::default().with_identifier("sm_a"));
+    let sm_b =
+        WebSocketMultiplexor::new(b_sink, b_stream, Config::default().with_identifier("sm_b"));
+
+    let listener = sm_b.bind(22).await.unwrap();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut request = vec![];
+        conn.read_to_end(&mut request).await.unwrap();
+        conn.write_all(&request).await.unwrap();
+        conn.write_all(b" world!").await.unwrap();
+        conn.shutdown().await.unwrap();
+    });
+
+    let mut conn = sm_a.connect(22).await.unwrap();
+    conn.write_all(b"Hello,").await.unwrap();
+    conn.shutdown().await.unwrap();
+    let mut response = vec![];
+    conn.read_to_end(&mut response).await.unwrap();
+
+    assert_eq!(response, b"Hello, world!");
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn split_stream_reports_ports() {
+    let (a, b) = duplex(10);
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+    let (b_sink, b_stream) = b_ws.split();
+
+    let sm_a =
+        WebSocketMultiplexor::new(a_sink, a_stream, Config::default().with_identifier("sm_a"));
+    let sm_b =
+        WebSocketMultiplexor::new(b_sink, b_stream, Config::default().with_identifier("sm_b"));
+
+    let listener = sm_b.bind(22).await.unwrap();
+    let (tx, mut rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let (conn, _) = listener.accept().await.unwrap();
+        tx.send((conn.local_port(), conn.peer_port()))
+            .await
+            .unwrap();
+        let (mut read, mut write) = conn.into_split();
+        tokio::io::copy(&mut read, &mut write).await.unwrap();
+    });
+
+    let conn = sm_a.connect(22).await.unwrap();
+    assert_eq!(conn.peer_port(), 22);
+    assert_eq!(
+        rx.recv().await.unwrap(),
+        (conn.peer_port(), conn.local_port())
+    );
+
+    let (mut read, mut write) = conn.into_split();
+    tokio::spawn(async move {
+        write.write_all(b"Hello, world!").await.unwrap();
+    });
+    let mut buf = [0u8; 13];
+    read.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"Hello, world!");
 }
 
 #[tokio::test]
@@ -118,7 +206,7 @@ async fn connected_stream_passes_data() {
 
     let input_bytes_clone = input_bytes.clone();
     tokio::spawn(async move {
-        let mut conn = sm_b.bind(22).await.unwrap().accept().await.unwrap();
+        let (mut conn, _) = sm_b.bind(22).await.unwrap().accept().await.unwrap();
         let mut i = 0;
         while i < input_bytes_clone.len() {
             let res = conn.write_all(&input_bytes_clone[i..i + 1024]).await;
@@ -146,6 +234,34 @@ async fn connected_stream_passes_data() {
     assert_eq!(input_bytes, output_bytes);
 }
 
+#[tokio::test]
+#[tracing::instrument]
+async fn io_transport_passes_data() {
+    let (a, b) = duplex(10);
+
+    let input_bytes: Vec<u8> = (0..(256 * 1024)).map(|_| rand::random::<u8>()).collect();
+    let len = input_bytes.len();
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b"));
+
+    let input_bytes_clone = input_bytes.clone();
+    tokio::spawn(async move {
+        let (mut conn, _) = sm_b.bind(22).await.unwrap().accept().await.unwrap();
+        conn.write_all(&input_bytes_clone).await.unwrap();
+        conn.shutdown().await.unwrap();
+        conn.read_i8().await.unwrap();
+    });
+
+    let mut conn = sm_a.connect(22).await.unwrap();
+    let mut output_bytes: Vec<u8> = vec![];
+    conn.read_to_end(&mut output_bytes).await.unwrap();
+    conn.write_i8(0).await.unwrap();
+
+    assert_eq!(len, output_bytes.len());
+    assert_eq!(input_bytes, output_bytes);
+}
+
 #[tokio::test]
 #[tracing::instrument]
 async fn wrapped_stream_disconnect() {
@@ -221,28 +337,26 @@ async fn wrapped_stream_disconnect_after_bind_connect_accept() {
         let exit_tx_clone = exit_tx.clone();
         tokio::spawn(async move {
             info!("spawn 1");
-            while let Ok(mut stream) = listener22.accept().await {
+            if let Ok((mut stream, _)) = listener22.accept().await {
                 info!("accept 1");
                 stream
                     .write_all(b"Hello, ")
                     .await
                     .expect("stream.write_all succeeds");
                 info!("write_all 1");
-                break;
             }
             exit_tx_clone.send(()).await.expect("exit_tx.send succeeds");
         });
 
         tokio::spawn(async move {
             info!("spawn 2");
-            while let Ok(mut stream) = listener23.accept().await {
+            if let Ok((mut stream, _)) = listener23.accept().await {
                 info!("accept 2");
                 stream
                     .write_all(b"world!\n")
                     .await
                     .expect("stream.write_all succeeds");
                 info!("write_all 2");
-                break;
             }
             exit_tx.send(()).await.expect("exit_tx.send succeeds");
         });
@@ -286,7 +400,7 @@ async fn wrapped_stream_disconnect_after_bind_connect_accept() {
 
     sleep(Duration::from_millis(100)).await;
 
-    assert_eq!(*watch_connected.borrow(), false);
+    assert!(!*watch_connected.borrow());
 }
 
 #[tokio::test]
@@ -307,14 +421,14 @@ async fn wrapped_stream_disconnect_subscribe_before() {
         WebSocketMultiplexor::new(a_sink, a_stream, Config::default().with_identifier("sm_a"));
 
     let mut connected = sm_a.watch_connected();
-    assert_eq!(*connected.borrow(), true);
+    assert!(*connected.borrow());
 
     let listener = sm_a.bind(1024).await.unwrap();
 
     sleep(Duration::from_millis(100)).await;
 
     assert!(matches!(connected.changed().await, Ok(())));
-    assert_eq!(*connected.borrow(), false);
+    assert!(!*connected.borrow());
     assert!(matches!(listener.accept().await, Err(..)));
 }
 
@@ -341,7 +455,7 @@ async fn wrapped_stream_disconnect_subscribe_after() {
 
     let connected = sm_a.watch_connected();
 
-    assert_eq!(*connected.borrow(), false);
+    assert!(!*connected.borrow());
 
     assert!(matches!(listener.accept().await, Err(..)));
 }
@@ -368,7 +482,7 @@ async fn listen_accept_multiple() {
         let listener = sm_b.bind(22).await.unwrap();
         loop {
             let input_bytes_clone = input_bytes_clone.clone();
-            let mut conn = listener.accept().await.unwrap();
+            let (mut conn, _) = listener.accept().await.unwrap();
             tokio::spawn(async move {
                 let mut i = 0;
                 while i < input_bytes_clone.len() {
@@ -417,7 +531,7 @@ async fn listen_multiple_accept() {
     let input_bytes_clone = input_bytes.clone();
     let listener = sm_b.bind(22).await.unwrap();
     tokio::spawn(async move {
-        let mut conn = listener.accept().await.unwrap();
+        let (mut conn, _) = listener.accept().await.unwrap();
         tokio::spawn(async move {
             let mut i = 0;
             while i < input_bytes_clone.len() {
@@ -431,7 +545,7 @@ async fn listen_multiple_accept() {
     let input_bytes_clone = input_bytes.clone();
     let listener = sm_b.bind(23).await.unwrap();
     tokio::spawn(async move {
-        let mut conn = listener.accept().await.unwrap();
+        let (mut conn, _) = listener.accept().await.unwrap();
         tokio::spawn(async move {
             let mut i = 0;
             while i < input_bytes_clone.len() {
@@ -496,7 +610,7 @@ async fn test_start_paused() {
     let connected_clone = connected.clone();
     tokio::spawn(async move {
         trace!("connect");
-        assert!(matches!(sm_a.connect(22).await, Ok(_)));
+        assert!(sm_a.connect(22).await.is_ok());
         connected_clone.store(true, Ordering::Relaxed);
         trace!("connected");
     });
@@ -515,3 +629,1608 @@ async fn test_start_paused() {
     assert!(accepted.load(Ordering::Relaxed));
     assert!(connected.load(Ordering::Relaxed));
 }
+
+#[tokio::test]
+#[tracing::instrument]
+async fn slow_reader_does_not_block_other_streams() {
+    // tokio-tungstenite can lose wakeups when both directions are saturated
+    // over a tiny pipe, which window updates make likely here.
+    let (a, b) = duplex(64 * 1024);
+
+    let input_bytes: Vec<u8> = (0..(1024 * 1024)).map(|_| rand::random::<u8>()).collect();
+    let len = input_bytes.len();
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+    let (b_sink, b_stream) = b_ws.split();
+
+    let config = Config {
+        initial_window_size: 64 * 1024,
+        ..Config::default()
+    };
+    let sm_a = WebSocketMultiplexor::new(a_sink, a_stream, config.with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::new(b_sink, b_stream, config.with_identifier("sm_b"));
+
+    let listener22 = sm_b.bind(22).await.unwrap();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener22.accept().await.unwrap();
+        // Nobody ever reads this on the other side
+        let _ = conn.write_all(&[0u8; 8 * 1024 * 1024]).await;
+    });
+    let input_bytes_clone = input_bytes.clone();
+    let listener23 = sm_b.bind(23).await.unwrap();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener23.accept().await.unwrap();
+        conn.write_all(&input_bytes_clone).await.unwrap();
+    });
+
+    let _conn22 = sm_a.connect(22).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    let mut conn23 = sm_a.connect(23).await.unwrap();
+    let mut output_bytes = vec![0u8; len];
+    tokio::time::timeout(Duration::from_secs(5), conn23.read_exact(&mut output_bytes))
+        .await
+        .expect("stream 23 is not blocked by stream 22")
+        .unwrap();
+
+    assert_eq!(input_bytes, output_bytes);
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn shutdown_drains_open_streams() {
+    let (a, b) = duplex(10);
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b"));
+
+    let listener = sm_b.bind(22).await.unwrap();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut request = vec![];
+        conn.read_to_end(&mut request).await.unwrap();
+        conn.write_all(b"Goodbye!").await.unwrap();
+        conn.shutdown().await.unwrap();
+    });
+
+    let mut conn = sm_a.connect(22).await.unwrap();
+    let mut watch_connected = sm_b.watch_connected();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let (res, response) = tokio::join!(sm_a.shutdown(deadline), async {
+        let mut response = vec![];
+        conn.read_to_end(&mut response).await.unwrap();
+        response
+    });
+
+    assert!(res.is_ok());
+    assert_eq!(response, b"Goodbye!");
+    assert!(sm_a.connect(22).await.is_err());
+    timeout(
+        Duration::from_secs(1),
+        watch_connected.wait_for(|connected| !connected),
+    )
+    .await
+    .expect("transport is closed after shutdown")
+    .unwrap();
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn shutdown_resets_after_deadline() {
+    let (a, b) = duplex(10);
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b"));
+
+    let listener = sm_b.bind(22).await.unwrap();
+    tokio::spawn(async move {
+        // Never shut down our side
+        let _conn = listener.accept().await.unwrap();
+        sleep(Duration::from_secs(5)).await;
+    });
+
+    let mut conn = sm_a.connect(22).await.unwrap();
+    let deadline = Instant::now() + Duration::from_millis(100);
+    let res = sm_a.shutdown(deadline).await;
+
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+    let mut buf = [0u8; 1];
+    assert!(conn.read(&mut buf).await.is_err());
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn shutdown_resets_connecting_streams() {
+    let (a, mut b) = duplex(1024);
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    // The peer reads but never answers the Syn
+    tokio::spawn(async move { tokio::io::copy(&mut b, &mut tokio::io::sink()).await });
+
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(5);
+    let (connected, res) = tokio::join!(sm_a.connect(22), async {
+        sleep(Duration::from_millis(50)).await;
+        sm_a.shutdown(deadline).await
+    });
+
+    assert!(connected.is_err());
+    assert!(res.is_ok());
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn keepalive_keeps_live_peer() {
+    let (a, b) = duplex(1024);
+    let config = Config {
+        keepalive_timeout: Duration::from_millis(50),
+        keepalive_max_missed: 2,
+        ..Config::default().with_keepalive(Duration::from_millis(20))
+    };
+
+    let sm_a = WebSocketMultiplexor::from_io(a, config.with_identifier("sm_a"));
+    let _sm_b = WebSocketMultiplexor::from_io(b, config.with_identifier("sm_b"));
+
+    sleep(Duration::from_millis(300)).await;
+    assert!(*sm_a.watch_connected().borrow());
+    assert_eq!(sm_a.disconnect_reason(), None);
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn keepalive_detects_dead_peer() {
+    // The peer never reads nor answers
+    let (a, _b) = duplex(1024);
+    let config = Config {
+        keepalive_timeout: Duration::from_millis(50),
+        keepalive_max_missed: 2,
+        ..Config::default().with_keepalive(Duration::from_millis(20))
+    };
+
+    let sm_a = WebSocketMultiplexor::from_io(a, config.with_identifier("sm_a"));
+    let mut watch_connected = sm_a.watch_connected();
+
+    timeout(
+        Duration::from_secs(1),
+        watch_connected.wait_for(|connected| !connected),
+    )
+    .await
+    .expect("dead peer is detected")
+    .unwrap();
+    assert_eq!(
+        sm_a.disconnect_reason(),
+        Some(DisconnectReason::KeepaliveTimeout)
+    );
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn websocket_close_is_propagated() {
+    let (a, b) = duplex(1024);
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let mut b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+
+    let sm_a =
+        WebSocketMultiplexor::new(a_sink, a_stream, Config::default().with_identifier("sm_a"));
+    let mut watch_connected = sm_a.watch_connected();
+
+    // A ping from e.g. a proxy is answered by the transport
+    b_ws.send(Message::Ping(b"ping".to_vec())).await.unwrap();
+    let pong = loop {
+        match b_ws.next().await.unwrap().unwrap() {
+            // Hello
+            Message::Binary(_) => continue,
+            message => break message,
+        }
+    };
+    assert_eq!(pong, Message::Pong(b"ping".to_vec()));
+    assert!(*watch_connected.borrow());
+
+    b_ws.send(Message::Close(Some(CloseFrame {
+        code: CloseCode::Away,
+        reason: "going away".into(),
+    })))
+    .await
+    .unwrap();
+    timeout(
+        Duration::from_secs(1),
+        watch_connected.wait_for(|connected| !connected),
+    )
+    .await
+    .expect("close is noticed")
+    .unwrap();
+    assert_eq!(
+        sm_a.disconnect_reason(),
+        Some(DisconnectReason::RemoteClosed {
+            code: Some(1001),
+            reason: "going away".into()
+        })
+    );
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn websocket_text_is_rejected() {
+    let (a, b) = duplex(1024);
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let mut b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+
+    let sm_a =
+        WebSocketMultiplexor::new(a_sink, a_stream, Config::default().with_identifier("sm_a"));
+    let mut watch_connected = sm_a.watch_connected();
+
+    b_ws.send(Message::Text("hello".into())).await.unwrap();
+    timeout(
+        Duration::from_secs(1),
+        watch_connected.wait_for(|connected| !connected),
+    )
+    .await
+    .expect("text message is rejected")
+    .unwrap();
+    assert_eq!(
+        sm_a.disconnect_reason(),
+        Some(DisconnectReason::Protocol(FrameError::UnexpectedText))
+    );
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn malformed_frames_disconnect() {
+    let cases = [
+        (vec![1, 0, 22], FrameError::Truncated(3)),
+        (
+            vec![1, 0, 22, 0, 22, 42, 0, 0, 0, 0],
+            FrameError::UnknownFlag(42),
+        ),
+        (
+            vec![9, 0, 22, 0, 22, 0, 0, 0, 0, 0],
+            FrameError::BadVersion(9),
+        ),
+        (
+            vec![1, 0, 22, 0, 22, 4, 0, 0, 0, 0],
+            FrameError::MissingHello,
+        ),
+    ];
+    for (data, error) in cases {
+        let (a, b) = duplex(4096);
+        let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+        let (a_sink, a_stream) = a_ws.split();
+        let mut b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+
+        let sm_a =
+            WebSocketMultiplexor::new(a_sink, a_stream, Config::default().with_identifier("sm_a"));
+        let mut watch_connected = sm_a.watch_connected();
+
+        b_ws.send(Message::Binary(data)).await.unwrap();
+        timeout(
+            Duration::from_secs(1),
+            watch_connected.wait_for(|connected| !connected),
+        )
+        .await
+        .expect("malformed frame is rejected")
+        .unwrap();
+        assert_eq!(
+            sm_a.disconnect_reason(),
+            Some(DisconnectReason::Protocol(error))
+        );
+    }
+}
+
+/// Encode a frame by hand, as a peer would put it on the wire.
+fn raw_frame(sport: u16, dport: u16, flag: u8, seq: u32, data: &[u8]) -> Message {
+    let mut frame = vec![1];
+    frame.extend_from_slice(&sport.to_be_bytes());
+    frame.extend_from_slice(&dport.to_be_bytes());
+    frame.push(flag);
+    frame.extend_from_slice(&seq.to_be_bytes());
+    frame.extend_from_slice(data);
+    Message::Binary(frame)
+}
+
+/// Encode a HELLO by hand, as a peer would put it on the wire.
+fn raw_hello(version: u16, min_version: u16, max_frame_size: u32) -> Message {
+    let mut hello = vec![];
+    hello.extend_from_slice(&version.to_be_bytes());
+    hello.extend_from_slice(&min_version.to_be_bytes());
+    hello.extend_from_slice(&Features::FLOW_CONTROL.bits().to_be_bytes());
+    hello.extend_from_slice(&max_frame_size.to_be_bytes());
+    raw_frame(0, 0, 9, 0, &hello)
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn oversized_frame_resets_stream() {
+    let (a, b) = duplex(4096);
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let mut b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+
+    let config = Config {
+        max_frame_size: 1024,
+        ..Config::default().with_identifier("sm_a")
+    };
+    let sm_a = WebSocketMultiplexor::new(a_sink, a_stream, config);
+    let listener = sm_a.bind(22).await.unwrap();
+
+    b_ws.send(raw_hello(1, 1, 4 * 1024 * 1024)).await.unwrap();
+    let Message::Binary(hello) = b_ws.next().await.unwrap().unwrap() else {
+        panic!("expected a binary frame");
+    };
+    assert_eq!(hello[5], 9);
+
+    // Syn with a 64 KiB window, expect SynAck
+    b_ws.send(raw_frame(1024, 22, 0, 0, &(64u32 * 1024).to_be_bytes()))
+        .await
+        .unwrap();
+    let Message::Binary(syn_ack) = b_ws.next().await.unwrap().unwrap() else {
+        panic!("expected a binary frame");
+    };
+    assert_eq!(syn_ack[5], 1);
+    b_ws.send(raw_frame(1024, 22, 2, 1, &[])).await.unwrap();
+    let (mut conn, _) = listener.accept().await.unwrap();
+
+    b_ws.send(raw_frame(1024, 22, 5, 2, &[0u8; 2048]))
+        .await
+        .unwrap();
+    let rst = loop {
+        let Message::Binary(frame) = b_ws.next().await.unwrap().unwrap() else {
+            panic!("expected a binary frame");
+        };
+        if frame[5] != 2 {
+            break frame;
+        }
+    };
+    assert_eq!(rst[5], 3);
+    let mut buf = [0u8; 16];
+    assert!(conn.read(&mut buf).await.is_err());
+    assert!(*sm_a.watch_connected().borrow());
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn oversized_mux_frame_disconnects() {
+    let (a, b) = duplex(4096);
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let mut b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+
+    let config = Config {
+        max_frame_size: 1024,
+        ..Config::default().with_identifier("sm_a")
+    };
+    let sm_a = WebSocketMultiplexor::new(a_sink, a_stream, config);
+    let mut watch_connected = sm_a.watch_connected();
+
+    b_ws.send(raw_hello(1, 1, 4 * 1024 * 1024)).await.unwrap();
+    // A Ping belongs to no stream, so there is nothing to reset
+    b_ws.send(raw_frame(0, 0, 7, 1, &[0u8; 2048]))
+        .await
+        .unwrap();
+    timeout(
+        Duration::from_secs(1),
+        watch_connected.wait_for(|connected| !connected),
+    )
+    .await
+    .expect("oversized Ping disconnects")
+    .unwrap();
+    assert_eq!(
+        sm_a.disconnect_reason(),
+        Some(DisconnectReason::Protocol(FrameError::Oversize(1024)))
+    );
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn out_of_order_frame_resets_stream() {
+    let (a, b) = duplex(4096);
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let mut b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+
+    let sm_a =
+        WebSocketMultiplexor::new(a_sink, a_stream, Config::default().with_identifier("sm_a"));
+    let listener = sm_a.bind(22).await.unwrap();
+
+    b_ws.send(raw_hello(1, 1, 4 * 1024 * 1024)).await.unwrap();
+    b_ws.next().await.unwrap().unwrap();
+    b_ws.send(raw_frame(1024, 22, 0, 0, &(64u32 * 1024).to_be_bytes()))
+        .await
+        .unwrap();
+    let Message::Binary(syn_ack) = b_ws.next().await.unwrap().unwrap() else {
+        panic!("expected a binary frame");
+    };
+    assert_eq!(syn_ack[5], 1);
+    b_ws.send(raw_frame(1024, 22, 2, 1, &[])).await.unwrap();
+    let (mut conn, _) = listener.accept().await.unwrap();
+
+    // Data frame 2 is lost
+    b_ws.send(raw_frame(1024, 22, 5, 3, b"hello"))
+        .await
+        .unwrap();
+    let rst = loop {
+        let Message::Binary(frame) = b_ws.next().await.unwrap().unwrap() else {
+            panic!("expected a binary frame");
+        };
+        if frame[5] != 2 {
+            break frame;
+        }
+    };
+    assert_eq!(rst[5], 3);
+    assert_eq!(
+        Frame::try_from(rst).unwrap().reset_reason(),
+        ResetReason::OutOfOrder {
+            expected: 2,
+            received: 3
+        }
+    );
+    let mut buf = [0u8; 16];
+    let error = conn.read(&mut buf).await.unwrap_err();
+    assert_eq!(
+        ResetReason::from_io_error(&error),
+        Some(ResetReason::OutOfOrder {
+            expected: 2,
+            received: 3
+        })
+    );
+    assert!(*sm_a.watch_connected().borrow());
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn writes_fit_max_frame_size() {
+    let (a, b) = duplex(4096);
+
+    let input_bytes: Vec<u8> = (0..(64 * 1024)).map(|_| rand::random::<u8>()).collect();
+    let config = Config {
+        max_frame_size: 1024,
+        ..Config::default()
+    };
+    // Byte streams disconnect on oversized frames, so every frame must fit
+    // the smaller limit
+    let sm_a = WebSocketMultiplexor::from_io(a, config.with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b"));
+
+    let input_bytes_clone = input_bytes.clone();
+    let listener = sm_b.bind(22).await.unwrap();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        conn.write_all(&input_bytes_clone).await.unwrap();
+        conn.shutdown().await.unwrap();
+    });
+
+    let mut conn = sm_a.connect(22).await.unwrap();
+    let mut output_bytes = vec![];
+    conn.read_to_end(&mut output_bytes).await.unwrap();
+
+    assert_eq!(input_bytes, output_bytes);
+    assert!(*sm_b.watch_connected().borrow());
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn oversized_syn_fails_connect() {
+    let (a, b) = duplex(4096);
+    let config = Config {
+        max_frame_size: 1024,
+        ..Config::default()
+    };
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, config.with_identifier("sm_b"));
+    let _listener = sm_b.bind(22).await.unwrap();
+
+    // Fits our own limit but not the one agreed on with the peer
+    let options = ConnectOptions::default().with_metadata(vec![0u8; 2048]);
+    let res = timeout(Duration::from_secs(1), sm_a.connect_with(22, options))
+        .await
+        .expect("connect fails instead of waiting for a dropped Syn");
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    assert!(sm_a.connect(22).await.is_ok());
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn hello_negotiates_parameters() {
+    let (a, b) = duplex(4096);
+    let config = Config {
+        max_frame_size: 64 * 1024,
+        ..Config::default()
+    };
+
+    let sm_a = WebSocketMultiplexor::from_io(a, config.with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b"));
+
+    let listener = sm_b.bind(22).await.unwrap();
+    tokio::spawn(async move {
+        let _conn = listener.accept().await;
+    });
+    // The handshake implies both Hellos were processed
+    sm_a.connect(22).await.unwrap();
+
+    let negotiated = sm_a.negotiated().unwrap();
+    assert_eq!(negotiated, sm_b.negotiated().unwrap());
+    assert_eq!(negotiated.version, 1);
+    assert_eq!(negotiated.max_frame_size, 64 * 1024);
+    assert!(negotiated
+        .features
+        .contains(Features::FLOW_CONTROL | Features::KEEPALIVE));
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn incompatible_peer_disconnects() {
+    let (a, b) = duplex(4096);
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let mut b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+
+    let sm_a =
+        WebSocketMultiplexor::new(a_sink, a_stream, Config::default().with_identifier("sm_a"));
+    let mut watch_connected = sm_a.watch_connected();
+
+    b_ws.send(raw_hello(7, 5, 1024)).await.unwrap();
+    timeout(
+        Duration::from_secs(1),
+        watch_connected.wait_for(|connected| !connected),
+    )
+    .await
+    .expect("incompatible peer is rejected")
+    .unwrap();
+    assert_eq!(
+        sm_a.disconnect_reason(),
+        Some(DisconnectReason::Incompatible {
+            version: 7,
+            min_version: 5
+        })
+    );
+    assert_eq!(sm_a.negotiated(), None);
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn tiny_max_frame_size_disconnects() {
+    let (a, b) = duplex(4096);
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let mut b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+
+    let sm_a =
+        WebSocketMultiplexor::new(a_sink, a_stream, Config::default().with_identifier("sm_a"));
+    let mut watch_connected = sm_a.watch_connected();
+
+    // Not even a Syn would fit
+    b_ws.send(raw_hello(1, 1, 8)).await.unwrap();
+    timeout(
+        Duration::from_secs(1),
+        watch_connected.wait_for(|connected| !connected),
+    )
+    .await
+    .expect("peer with a tiny max_frame_size is rejected")
+    .unwrap();
+    assert_eq!(
+        sm_a.disconnect_reason(),
+        Some(DisconnectReason::Protocol(FrameError::FrameTooSmall(8)))
+    );
+    assert!(sm_a.connect(22).await.is_err());
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn connect_with_times_out() {
+    let (a, b) = duplex(4096);
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    // Never answers the Syn
+    let _sm_b = WebSocketMultiplexor::from_io_paused(b, Config::default().with_identifier("sm_b"));
+
+    let options = ConnectOptions::default()
+        .with_timeout(Duration::from_millis(100))
+        .with_source_port(4000);
+    let res = sm_a.connect_with(22, options.clone()).await;
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+    // The half-open connection is gone, so the port is free again
+    let res = sm_a.connect_with(22, options).await;
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn connect_with_times_out_negotiating() {
+    // The peer never sends its HELLO
+    let (a, _b) = duplex(4096);
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+
+    let options = ConnectOptions::default()
+        .with_timeout(Duration::from_millis(100))
+        .with_metadata(b"hello".as_slice());
+    let res = timeout(Duration::from_secs(1), sm_a.connect_with(22, options))
+        .await
+        .expect("the timeout covers negotiation");
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn connect_with_passes_options() {
+    let (a, b) = duplex(4096);
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b"));
+
+    let listener = sm_b.bind(22).await.unwrap();
+    let (tx, mut rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let (conn, peer) = listener.accept().await.unwrap();
+        assert_eq!(conn.peer_port(), peer.port);
+        tx.send(peer).await.unwrap();
+    });
+
+    let options = ConnectOptions::default()
+        .with_timeout(Duration::from_secs(1))
+        .with_source_port(4000)
+        .with_initial_window_size(1024)
+        .with_metadata(b"hello".as_slice());
+    let conn = sm_a.connect_with(22, options.clone()).await.unwrap();
+    assert_eq!(conn.local_port(), 4000);
+    assert_eq!(conn.metadata(), b"hello");
+    assert_eq!(
+        rx.recv().await.unwrap(),
+        PeerInfo {
+            port: 4000,
+            metadata: b"hello".to_vec()
+        }
+    );
+
+    let res = sm_a.connect_with(22, options).await;
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::AddrInUse);
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn reset_reasons_map_to_error_kinds() {
+    let (a, b) = duplex(4096);
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b"));
+
+    let error = sm_a.connect(22).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+    assert_eq!(
+        ResetReason::from_io_error(&error),
+        Some(ResetReason::Refused)
+    );
+
+    let listener = sm_b.bind(22).await.unwrap();
+    tokio::spawn(async move {
+        let (conn, _) = listener.accept().await.unwrap();
+        conn.abort(42).await;
+    });
+    let mut conn = sm_a.connect(22).await.unwrap();
+    let mut buf = [0u8; 16];
+    let error = conn.read(&mut buf).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted);
+    assert_eq!(
+        ResetReason::from_io_error(&error),
+        Some(ResetReason::Abort(42))
+    );
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn full_backlog_refuses_without_blocking() {
+    let (a, b) = duplex(4096);
+    let config = Config {
+        accept_queue_len: 2,
+        ..Config::default()
+    };
+
+    let sm_a = WebSocketMultiplexor::from_io(a, config.with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, config.with_identifier("sm_b"));
+
+    // Nobody accepts on port 22
+    let listener22 = sm_b.bind(22).await.unwrap();
+    let _conn0 = sm_a.connect(22).await.unwrap();
+    let _conn1 = sm_a.connect(22).await.unwrap();
+    assert_eq!(listener22.backlog(), 2);
+    let error = sm_a.connect(22).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+    assert_eq!(
+        ResetReason::from_io_error(&error),
+        Some(ResetReason::QueueFull)
+    );
+
+    // Other ports keep working
+    let listener23 = sm_b.bind(23).await.unwrap();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener23.accept().await.unwrap();
+        conn.write_all(b"Hello, world!").await.unwrap();
+    });
+    let mut conn = sm_a.connect(23).await.unwrap();
+    let mut buf = [0u8; 13];
+    timeout(Duration::from_secs(1), conn.read_exact(&mut buf))
+        .await
+        .expect("reader is not blocked by the full backlog")
+        .unwrap();
+    assert_eq!(&buf, b"Hello, world!");
+
+    listener22.accept().await.unwrap();
+    assert_eq!(listener22.backlog(), 1);
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn listener_is_a_stream() {
+    let (a, b) = duplex(4096);
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b"));
+
+    let mut listener = sm_b.bind(22).await.unwrap();
+    let server = tokio::spawn(async move {
+        listener
+            .incoming()
+            .take(3)
+            .for_each_concurrent(None, |conn| async move {
+                conn.unwrap().write_all(b"Hello").await.unwrap();
+            })
+            .await;
+        // Ends once the multiplexor goes away
+        assert!(listener.next().await.is_none());
+    });
+
+    for _ in 0..3 {
+        let mut conn = sm_a.connect(22).await.unwrap();
+        let mut buf = [0u8; 5];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"Hello");
+    }
+    sm_b.close();
+    timeout(Duration::from_secs(1), server)
+        .await
+        .expect("listener stream ends")
+        .unwrap();
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn named_services_connect_and_enumerate() {
+    let (a, b) = duplex(4096);
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b"));
+
+    let metrics = sm_b.bind_named("metrics").await.unwrap();
+    let _logs = sm_b.bind_named("logs").await.unwrap();
+    assert_eq!(
+        sm_b.bind_named("metrics").await.unwrap_err().kind(),
+        std::io::ErrorKind::AddrInUse
+    );
+    assert_eq!(sm_a.peer_names().await.unwrap(), ["logs", "metrics"]);
+
+    let server = tokio::spawn(async move {
+        let (mut stream, peer) = metrics.accept().await.unwrap();
+        assert_eq!(stream.local_port(), metrics.port());
+        stream.write_all(&peer.port.to_be_bytes()).await.unwrap();
+        metrics
+    });
+    let mut stream = sm_a.connect_named("metrics").await.unwrap();
+    assert_eq!(stream.peer_port(), 0);
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(u16::from_be_bytes(buf), stream.local_port());
+
+    assert_eq!(
+        sm_a.connect_named("missing").await.unwrap_err().kind(),
+        std::io::ErrorKind::ConnectionRefused
+    );
+
+    // Dropping the listener releases its name
+    drop(server.await.unwrap());
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(sm_a.peer_names().await.unwrap(), ["logs"]);
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn bind_named_on_paused_mux() {
+    let (a, b) = duplex(4096);
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io_paused(b, Config::default().with_identifier("sm_b"));
+
+    let _metrics = timeout(Duration::from_secs(1), sm_b.bind_named("metrics"))
+        .await
+        .expect("binding does not wait for the peer")
+        .unwrap();
+    sm_b.start();
+    assert_eq!(sm_a.peer_names().await.unwrap(), ["metrics"]);
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn names_must_fit_in_a_frame() {
+    let (a, b) = duplex(4096);
+    let config = Config {
+        max_frame_size: 1024,
+        ..Config::default()
+    };
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, config.with_identifier("sm_b"));
+
+    assert_eq!(
+        sm_b.bind_named(&"x".repeat(2048)).await.unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+
+    // Each name fits, all of them do not
+    let mut listeners = vec![];
+    for i in 0..16 {
+        let name = format!("{i:0>100}");
+        listeners.push(sm_b.bind_named(&name).await.unwrap());
+    }
+    let error = timeout(Duration::from_secs(1), sm_a.peer_names())
+        .await
+        .expect("query fails instead of waiting for a dropped answer")
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    listeners.truncate(2);
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(sm_a.peer_names().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn remote_listeners_are_enumerated_and_watched() {
+    let (a, b) = duplex(4096);
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b"));
+
+    let _existing = sm_b.bind(80).await.unwrap();
+    let mut events = sm_a.subscribe_remote_listeners().await.unwrap();
+    assert_eq!(sm_a.remote_listeners().await.unwrap(), [80]);
+
+    let listener = sm_b.bind(22).await.unwrap();
+    assert_eq!(events.recv().await.unwrap(), ListenerEvent::Bound(22));
+    assert_eq!(sm_a.remote_listeners().await.unwrap(), [22, 80]);
+
+    drop(listener);
+    assert_eq!(events.recv().await.unwrap(), ListenerEvent::Unbound(22));
+    assert_eq!(sm_a.remote_listeners().await.unwrap(), [80]);
+
+    // The subscription ends with the multiplexor
+    sm_a.close();
+    assert!(timeout(Duration::from_secs(1), events.recv())
+        .await
+        .unwrap()
+        .is_err());
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn mismatched_answers_are_invalid_data() {
+    let (a, b) = duplex(4096);
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let mut b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+
+    let sm_a =
+        WebSocketMultiplexor::new(a_sink, a_stream, Config::default().with_identifier("sm_a"));
+
+    let mut hello = vec![];
+    hello.extend_from_slice(&1u16.to_be_bytes());
+    hello.extend_from_slice(&1u16.to_be_bytes());
+    let features = Features::NAMED_SERVICES | Features::REMOTE_LISTENERS;
+    hello.extend_from_slice(&features.bits().to_be_bytes());
+    hello.extend_from_slice(&(64u32 * 1024).to_be_bytes());
+    b_ws.send(raw_frame(0, 0, 9, 0, &hello)).await.unwrap();
+
+    // Answer every query with the other kind of answer
+    tokio::spawn(async move {
+        while let Some(Ok(Message::Binary(frame))) = b_ws.next().await {
+            if frame[5] != 10 {
+                continue;
+            }
+            let mut answer = vec![if frame[10] == 1 { 4 } else { 2 }];
+            answer.extend_from_slice(&frame[11..15]);
+            answer.extend_from_slice(&0u16.to_be_bytes());
+            b_ws.send(raw_frame(0, 0, 10, 0, &answer)).await.unwrap();
+        }
+    });
+
+    assert_eq!(
+        sm_a.peer_names().await.unwrap_err().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+    assert_eq!(
+        sm_a.remote_listeners().await.unwrap_err().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+    assert!(*sm_a.watch_connected().borrow());
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn syn_waits_for_bind() {
+    let (a, b) = duplex(4096);
+
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(
+        b,
+        Config::default()
+            .with_identifier("sm_b")
+            .with_unbound_syn_timeout(Duration::from_millis(500)),
+    );
+
+    let sm_a = Arc::new(sm_a);
+    let early = tokio::spawn({
+        let sm_a = sm_a.clone();
+        async move { sm_a.connect(22).await }
+    });
+    let early_named = tokio::spawn({
+        let sm_a = sm_a.clone();
+        async move { sm_a.connect_named("ssh").await }
+    });
+    sleep(Duration::from_millis(100)).await;
+    let listener = sm_b.bind(22).await.unwrap();
+    let named = sm_b.bind_named("ssh").await.unwrap();
+    let mut stream = early.await.unwrap().unwrap();
+    let (mut accepted, _) = listener.accept().await.unwrap();
+    accepted.write_all(b"Hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"Hello");
+    early_named.await.unwrap().unwrap();
+    named.accept().await.unwrap();
+
+    // Nothing is bound in time
+    let start = Instant::now();
+    assert_eq!(
+        sm_a.connect(23).await.unwrap_err().kind(),
+        std::io::ErrorKind::ConnectionRefused
+    );
+    assert!(start.elapsed() >= Duration::from_millis(500));
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn reconnecting_resumes_streams() {
+    // Many small frames, so some are in flight when the transport drops
+    let server_config = Config {
+        buf_size: 1024,
+        ..Config::default()
+    };
+    let sessions = Arc::new(Sessions::new(server_config.with_identifier("server")));
+    let (mux_send, mut mux_recv) = mpsc::unbounded_channel();
+    let (kill, _) = watch::channel(());
+    let kill = Arc::new(kill);
+    let dials = Arc::new(AtomicUsize::new(0));
+
+    let client = WebSocketMultiplexor::reconnecting(
+        {
+            let kill = kill.clone();
+            let dials = dials.clone();
+            move || {
+                let sessions = sessions.clone();
+                let mux_send = mux_send.clone();
+                let mut killed = kill.subscribe();
+                dials.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    let (a, b) = duplex(4096);
+                    tokio::spawn(async move {
+                        let b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+                        let (b_sink, b_stream) = b_ws.split();
+                        if let Some(mux) = sessions.accept(b_sink, b_stream).await.unwrap() {
+                            mux_send.send(mux).unwrap();
+                        }
+                    });
+                    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+                    let (a_sink, a_stream) = a_ws.split();
+                    // Drop the transport, and the frames in flight, on kill
+                    let a_stream = a_stream.take_until(async move {
+                        killed.changed().await.ok();
+                    });
+                    Ok((a_sink, a_stream))
+                }
+            }
+        },
+        Config::default().with_identifier("client"),
+    );
+
+    let server = mux_recv.recv().await.unwrap();
+    let listener = server.bind(22).await.unwrap();
+    let mut stream = client.connect(22).await.unwrap();
+    let (mut accepted, _) = listener.accept().await.unwrap();
+    assert!(client
+        .negotiated()
+        .unwrap()
+        .features
+        .contains(Features::RESUME));
+    assert!(server
+        .negotiated()
+        .unwrap()
+        .features
+        .contains(Features::RESUME));
+
+    let payload: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let expected = payload.clone();
+    let writer = tokio::spawn(async move {
+        accepted.write_all(&payload).await.unwrap();
+        accepted
+    });
+    let mut received = vec![0u8; expected.len()];
+    stream.read_exact(&mut received[..1000]).await.unwrap();
+    kill.send_replace(());
+    stream.read_exact(&mut received[1000..]).await.unwrap();
+    assert!(received == expected);
+
+    let mut accepted = writer.await.unwrap();
+    stream.write_all(b"Hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    accepted.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"Hello");
+
+    assert_eq!(dials.load(Ordering::SeqCst), 2);
+    assert_eq!(client.disconnect_reason(), None);
+    assert_eq!(server.disconnect_reason(), None);
+    // The server only created one multiplexor
+    assert!(mux_recv.try_recv().is_err());
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn reconnecting_gives_up_after_resume_timeout() {
+    let config = Config::default().with_resume_timeout(Duration::from_millis(300));
+    let sessions = Arc::new(Sessions::new(config.with_identifier("server")));
+    let (mux_send, mut mux_recv) = mpsc::unbounded_channel();
+    let (kill, _) = watch::channel(());
+    let kill = Arc::new(kill);
+    let dialed = Arc::new(AtomicBool::new(false));
+
+    let client = WebSocketMultiplexor::reconnecting(
+        {
+            let kill = kill.clone();
+            move || {
+                let sessions = sessions.clone();
+                let mux_send = mux_send.clone();
+                let mut killed = kill.subscribe();
+                let first = !dialed.swap(true, Ordering::SeqCst);
+                async move {
+                    if !first {
+                        return Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+                    }
+                    let (a, b) = duplex(4096);
+                    tokio::spawn(async move {
+                        let b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+                        let (b_sink, b_stream) = b_ws.split();
+                        if let Some(mux) = sessions.accept(b_sink, b_stream).await.unwrap() {
+                            mux_send.send(mux).unwrap();
+                        }
+                    });
+                    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+                    let (a_sink, a_stream) = a_ws.split();
+                    let a_stream = a_stream.take_until(async move {
+                        killed.changed().await.ok();
+                    });
+                    Ok((a_sink, a_stream))
+                }
+            }
+        },
+        config.with_identifier("client"),
+    );
+
+    let server = mux_recv.recv().await.unwrap();
+    let _listener = server.bind(22).await.unwrap();
+    let mut stream = client.connect(22).await.unwrap();
+    kill.send_replace(());
+
+    let mut client_connected = client.watch_connected();
+    let mut server_connected = server.watch_connected();
+    timeout(
+        Duration::from_secs(2),
+        client_connected.wait_for(|connected| !connected),
+    )
+    .await
+    .expect("client gives up")
+    .unwrap();
+    timeout(
+        Duration::from_secs(2),
+        server_connected.wait_for(|connected| !connected),
+    )
+    .await
+    .expect("server gives up")
+    .unwrap();
+    assert_eq!(
+        client.disconnect_reason(),
+        Some(DisconnectReason::Transport(
+            std::io::ErrorKind::ConnectionRefused
+        ))
+    );
+    assert!(stream.write_all(b"Hello").await.is_err());
+}
+
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+#[tokio::test]
+#[tracing::instrument]
+async fn compressed_streams_round_trip() {
+    let codecs = [
+        #[cfg(feature = "deflate")]
+        Compression::Deflate,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd,
+    ];
+    let (a, b) = duplex(64 * 1024);
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b"));
+    let listener = sm_b.bind(22).await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((conn, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = conn.into_split();
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                writer.shutdown().await.unwrap();
+            });
+        }
+    });
+
+    let input_bytes = b"{\"level\":\"info\",\"message\":\"compress me\"}\n".repeat(4096);
+    for codec in codecs {
+        let conn = sm_a
+            .connect_with(22, ConnectOptions::default().with_compression(codec))
+            .await
+            .unwrap();
+        let (mut reader, mut writer) = conn.into_split();
+        let (_, output_bytes) = tokio::join!(
+            async {
+                writer.write_all(&input_bytes).await.unwrap();
+                writer.shutdown().await.unwrap();
+            },
+            async {
+                let mut output_bytes = vec![];
+                reader.read_to_end(&mut output_bytes).await.unwrap();
+                output_bytes
+            }
+        );
+        assert_eq!(input_bytes, output_bytes, "{codec:?}");
+    }
+}
+
+#[cfg(feature = "deflate")]
+#[tokio::test]
+#[tracing::instrument]
+async fn compressed_connect_times_out_negotiating() {
+    // The peer never sends its HELLO
+    let (a, _b) = duplex(4096);
+
+    let config = Config {
+        compression: Compression::Deflate,
+        ..Config::default().with_identifier("sm_a")
+    };
+    let sm_a = WebSocketMultiplexor::from_io(a, config);
+
+    let options = ConnectOptions::default().with_timeout(Duration::from_millis(100));
+    let res = timeout(Duration::from_secs(1), sm_a.connect_with(22, options))
+        .await
+        .expect("the timeout covers negotiation");
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+}
+
+#[cfg(feature = "deflate")]
+#[tokio::test]
+#[tracing::instrument]
+async fn compressed_frames_are_flagged() {
+    let (a, b) = duplex(64 * 1024);
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let mut b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+
+    let sm_a =
+        WebSocketMultiplexor::new(a_sink, a_stream, Config::default().with_identifier("sm_a"));
+    let listener = sm_a.bind(22).await.unwrap();
+
+    let mut hello = vec![0, 1, 0, 1];
+    hello.extend_from_slice(
+        &(Features::FLOW_CONTROL | Features::DEFLATE)
+            .bits()
+            .to_be_bytes(),
+    );
+    hello.extend_from_slice(&(1024u32 * 1024).to_be_bytes());
+    b_ws.send(raw_frame(0, 0, 9, 0, &hello)).await.unwrap();
+    b_ws.next().await.unwrap().unwrap();
+
+    // Syn asking for deflate
+    let mut syn = Vec::from((64u32 * 1024).to_be_bytes());
+    syn.extend_from_slice(&[3, 0, 1, 1]);
+    b_ws.send(raw_frame(1024, 22, 0, 0, &syn)).await.unwrap();
+    b_ws.next().await.unwrap().unwrap();
+    b_ws.send(raw_frame(1024, 22, 2, 1, &[])).await.unwrap();
+    let (mut conn, _) = listener.accept().await.unwrap();
+
+    let input_bytes = [b'a'; 4096];
+    let mut data = vec![1];
+    data.extend(Compression::Deflate.compress(&input_bytes).unwrap());
+    b_ws.send(raw_frame(1024, 22, 12, 2, &data)).await.unwrap();
+    let mut output_bytes = [0u8; 4096];
+    conn.read_exact(&mut output_bytes).await.unwrap();
+    assert_eq!(input_bytes, output_bytes);
+
+    // Our replies are compressed too, but small ones are not worth it
+    conn.write_all(b"small").await.unwrap();
+    conn.write_all(&input_bytes).await.unwrap();
+    let mut flags = vec![];
+    while flags.len() < 2 {
+        let Message::Binary(frame) = b_ws.next().await.unwrap().unwrap() else {
+            panic!("expected a binary frame");
+        };
+        if frame[5] != 2 && frame[5] != 6 {
+            flags.push(frame[5]);
+        }
+    }
+    assert_eq!(flags, [5, 12]);
+}
+
+#[cfg(feature = "noise")]
+#[tokio::test]
+#[tracing::instrument]
+async fn encrypted_streams_round_trip() {
+    let (a_private, a_public) = NoiseConfig::generate_keypair();
+    let (b_private, b_public) = NoiseConfig::generate_keypair();
+    let psk = [7u8; 32];
+    let (a, b) = duplex(64 * 1024);
+    let a_ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+    let b_ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+    let (a_sink, a_stream) = a_ws.split();
+    let (b_sink, b_stream) = b_ws.split();
+
+    let a_noise = NoiseConfig::initiator(a_private)
+        .with_remote_public_key(b_public)
+        .with_psk(psk);
+    let b_noise = NoiseConfig::responder(b_private).with_psk(psk);
+    let (sm_a, sm_b) = tokio::join!(
+        WebSocketMultiplexor::new_encrypted(
+            a_sink,
+            a_stream,
+            Config::default().with_identifier("sm_a"),
+            &a_noise,
+        ),
+        WebSocketMultiplexor::new_encrypted(
+            b_sink,
+            b_stream,
+            Config::default().with_identifier("sm_b"),
+            &b_noise,
+        )
+    );
+    let (sm_a, sm_b) = (sm_a.unwrap(), sm_b.unwrap());
+    assert_eq!(sm_a.peer_public_key(), Some(b_public));
+    assert_eq!(sm_b.peer_public_key(), Some(a_public));
+
+    // Frames larger than a Noise message are sealed in several
+    let input_bytes: Vec<u8> = (0..(256 * 1024)).map(|_| rand::random::<u8>()).collect();
+    let input_bytes_clone = input_bytes.clone();
+    let listener = sm_b.bind(22).await.unwrap();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        conn.write_all(&input_bytes_clone).await.unwrap();
+        conn.shutdown().await.unwrap();
+    });
+    let mut conn = sm_a.connect(22).await.unwrap();
+    let mut output_bytes = vec![];
+    conn.read_to_end(&mut output_bytes).await.unwrap();
+    assert_eq!(input_bytes, output_bytes);
+}
+
+#[cfg(feature = "noise")]
+#[tokio::test]
+#[tracing::instrument]
+async fn encryption_rejects_unexpected_peer() {
+    let (a_private, _) = NoiseConfig::generate_keypair();
+    let (b_private, _) = NoiseConfig::generate_keypair();
+    let (_, other_public) = NoiseConfig::generate_keypair();
+    let (a, b) = duplex(4096);
+
+    let a_noise = NoiseConfig::initiator(a_private).with_remote_public_key(other_public);
+    let b_noise = NoiseConfig::responder(b_private);
+    let (sm_a, sm_b) = tokio::join!(
+        WebSocketMultiplexor::from_io_encrypted(a, Config::default(), &a_noise),
+        WebSocketMultiplexor::from_io_encrypted(b, Config::default(), &b_noise)
+    );
+    assert_eq!(
+        sm_a.unwrap_err().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+    assert!(sm_b.is_err());
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn scheduler_leaves_frames_in_full_queue() {
+    let (send, mut scheduler) = scheduler::queue(2);
+    let ping = |seq| Frame::new_no_data(0, 0, Flag::Ping, seq);
+    for seq in 0..2 {
+        send.try_reserve().unwrap().send(ping(seq));
+    }
+
+    // Takes both frames, sends one and holds the other
+    let (frame, _) = scheduler.try_next(|_| Priority::default()).unwrap();
+    assert_eq!(frame.seq, 0);
+    assert_eq!(send.queued(), 1);
+    for seq in 2..4 {
+        send.try_reserve().unwrap().send(ping(seq));
+    }
+    assert_eq!(send.queued(), 3);
+
+    // Only takes a frame once it has room, the queue stays full
+    let (frame, _) = scheduler.try_next(|_| Priority::default()).unwrap();
+    assert_eq!(frame.seq, 1);
+    assert!(send.try_reserve().is_ok());
+    assert_eq!(send.queued(), 2);
+    for seq in 2..4 {
+        let (frame, _) = scheduler.try_next(|_| Priority::default()).unwrap();
+        assert_eq!(frame.seq, seq);
+    }
+    assert!(scheduler.try_next(|_| Priority::default()).is_none());
+    assert_eq!(send.queued(), 0);
+}
+
+/// How many bytes of a bulk transfer the peer reads while a message sent
+/// on a stream of priority `urgent` makes its way through.
+async fn bulk_bytes_ahead_of(urgent: Priority) -> usize {
+    let (a, b) = duplex(4096);
+    let config = Config {
+        buf_size: 1024,
+        max_queued_frames: 16,
+        ..Config::default()
+    };
+    let sm_a = WebSocketMultiplexor::from_io(a, config.with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, config.with_identifier("sm_b"));
+    let listener = sm_b.bind(22).await.unwrap();
+
+    // Never gives up its turn to a stream of the same class
+    let mut bulk = sm_a
+        .connect_with(
+            22,
+            ConnectOptions::default().with_priority(Priority::new(0, u16::MAX)),
+        )
+        .await
+        .unwrap();
+    let (mut bulk_peer, _) = listener.accept().await.unwrap();
+    let mut urgent = sm_a
+        .connect_with(22, ConnectOptions::default().with_priority(urgent))
+        .await
+        .unwrap();
+    let (mut urgent_peer, _) = listener.accept().await.unwrap();
+
+    let bulk_received = Arc::new(AtomicUsize::new(0));
+    let bulk_received_clone = bulk_received.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 4096];
+        while let Ok(read @ 1..) = bulk_peer.read(&mut buf).await {
+            bulk_received_clone.fetch_add(read, Ordering::Relaxed);
+        }
+    });
+    tokio::spawn(async move {
+        bulk.write_all(&[0u8; 1024 * 1024]).await.unwrap();
+    });
+    while bulk_received.load(Ordering::Relaxed) < 64 * 1024 {
+        sleep(Duration::from_millis(1)).await;
+    }
+
+    let before = bulk_received.load(Ordering::Relaxed);
+    urgent.write_all(b"urgent").await.unwrap();
+    let mut buf = [0u8; 6];
+    urgent_peer.read_exact(&mut buf).await.unwrap();
+    bulk_received.load(Ordering::Relaxed) - before
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn priority_streams_overtake_bulk_transfers() {
+    // In the same class the message waits for the rest of the bulk
+    // transfer, a higher class only for the frames queued before it
+    let same_class = bulk_bytes_ahead_of(Priority::new(0, 1)).await;
+    let higher_class = bulk_bytes_ahead_of(Priority::new(1, 1)).await;
+    info!(
+        "Urgent message waited for {} bulk bytes, {} in the same class",
+        higher_class, same_class
+    );
+    assert!(higher_class * 4 < same_class, "{higher_class} {same_class}");
+}
+
+#[tokio::test]
+#[tracing::instrument]
+async fn stats_count_frames_and_connections() {
+    let (a, b) = duplex(4096);
+    let sm_a = WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a"));
+    let sm_b = WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b"));
+    let listener = sm_b.bind(22).await.unwrap();
+
+    let mut conn_a = sm_a.connect(22).await.unwrap();
+    let (mut conn_b, _) = listener.accept().await.unwrap();
+    conn_a.write_all(&[1u8; 1000]).await.unwrap();
+    let mut buf = [0u8; 1000];
+    conn_b.read_exact(&mut buf).await.unwrap();
+    assert!(sm_a.connect(23).await.is_err());
+
+    let stats_a = sm_a.stats().await;
+    assert!(stats_a.frames_out >= 3);
+    assert!(stats_a.bytes_out >= 1000);
+    assert_eq!(stats_a.rsts_received, 1);
+    assert_eq!(stats_a.decode_errors, 0);
+    assert_eq!(stats_a.connections.len(), 1);
+    let connection = &stats_a.connections[0];
+    assert_eq!(connection.peer_port, 22);
+    assert_eq!(connection.state, ConnectionState::Open);
+    assert_eq!(connection.bytes_out, 1000);
+
+    let stats_b = sm_b.stats().await;
+    assert_eq!(stats_b.syns_refused, 1);
+    assert_eq!(stats_b.rsts_sent, 1);
+    assert_eq!(stats_b.frames_in, stats_a.frames_out);
+    assert_eq!(stats_b.connections[0].bytes_in, 1000);
+    assert_eq!(stats_b.connections[0].local_port, 22);
+}
+
+/// Keeps what is published through the `metrics` facade, by name and
+/// labels.
+#[cfg(feature = "metrics")]
+#[derive(Default)]
+struct TestRecorder {
+    counters:
+        std::sync::Mutex<std::collections::HashMap<String, Arc<std::sync::atomic::AtomicU64>>>,
+    histograms: std::sync::Mutex<std::collections::HashMap<String, Arc<TestHistogram>>>,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Default)]
+struct TestHistogram(std::sync::Mutex<Vec<f64>>);
+
+#[cfg(feature = "metrics")]
+impl metrics::HistogramFn for TestHistogram {
+    fn record(&self, value: f64) {
+        self.0.lock().unwrap().push(value);
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl TestRecorder {
+    fn name(key: &metrics::Key) -> String {
+        let labels: Vec<String> = key
+            .labels()
+            .map(|label| format!("{}={}", label.key(), label.value()))
+            .collect();
+        format!("{}{{{}}}", key.name(), labels.join(","))
+    }
+
+    fn counter(&self, name: &str) -> u64 {
+        self.counters
+            .lock()
+            .unwrap()
+            .get(name)
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    fn histogram(&self, name: &str) -> Vec<f64> {
+        self.histograms
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|histogram| histogram.0.lock().unwrap().clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl metrics::Recorder for TestRecorder {
+    fn describe_counter(
+        &self,
+        _: metrics::KeyName,
+        _: Option<metrics::Unit>,
+        _: metrics::SharedString,
+    ) {
+    }
+    fn describe_gauge(
+        &self,
+        _: metrics::KeyName,
+        _: Option<metrics::Unit>,
+        _: metrics::SharedString,
+    ) {
+    }
+    fn describe_histogram(
+        &self,
+        _: metrics::KeyName,
+        _: Option<metrics::Unit>,
+        _: metrics::SharedString,
+    ) {
+    }
+
+    fn register_counter(&self, key: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Counter {
+        let counter = self
+            .counters
+            .lock()
+            .unwrap()
+            .entry(Self::name(key))
+            .or_default()
+            .clone();
+        metrics::Counter::from_arc(counter)
+    }
+
+    fn register_gauge(&self, _: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Gauge {
+        metrics::Gauge::noop()
+    }
+
+    fn register_histogram(
+        &self,
+        key: &metrics::Key,
+        _: &metrics::Metadata<'_>,
+    ) -> metrics::Histogram {
+        let histogram = self
+            .histograms
+            .lock()
+            .unwrap()
+            .entry(Self::name(key))
+            .or_default()
+            .clone();
+        metrics::Histogram::from_arc(histogram)
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+#[tracing::instrument]
+async fn metrics_are_labelled_with_identifier() {
+    let recorder = TestRecorder::default();
+    let (a, b) = duplex(4096);
+    let (sm_a, sm_b) = metrics::with_local_recorder(&recorder, || {
+        (
+            WebSocketMultiplexor::from_io(a, Config::default().with_identifier("sm_a")),
+            WebSocketMultiplexor::from_io(b, Config::default().with_identifier("sm_b")),
+        )
+    });
+    let listener = sm_b.bind(22).await.unwrap();
+
+    let mut conn_a = sm_a.connect(22).await.unwrap();
+    let (mut conn_b, _) = listener.accept().await.unwrap();
+    conn_a.write_all(&[1u8; 1000]).await.unwrap();
+    let mut buf = [0u8; 1000];
+    conn_b.read_exact(&mut buf).await.unwrap();
+    assert!(sm_a.connect(23).await.is_err());
+
+    let stats_a = sm_a.stats().await;
+    assert_eq!(
+        recorder.counter("wsmux_frames_sent_total{mux=sm_a}"),
+        stats_a.frames_out
+    );
+    assert_eq!(recorder.counter("wsmux_rsts_received_total{mux=sm_a}"), 1);
+    assert_eq!(recorder.counter("wsmux_syns_refused_total{mux=sm_b}"), 1);
+    let sizes = recorder.histogram("wsmux_frame_size_bytes{mux=sm_a,direction=sent}");
+    assert_eq!(sizes.len() as u64, stats_a.frames_out);
+    assert!(sizes.iter().any(|&size| size >= 1000.0));
+    assert_eq!(
+        recorder
+            .histogram("wsmux_handshake_seconds{mux=sm_a}")
+            .len(),
+        1
+    );
+    assert!(!recorder
+        .histogram("wsmux_queue_wait_seconds{mux=sm_a}")
+        .is_empty());
+
+    drop(conn_a);
+    assert_eq!(conn_b.read(&mut buf).await.unwrap(), 0);
+    drop(conn_b);
+    timeout(Duration::from_secs(5), async {
+        while recorder
+            .histogram("wsmux_stream_lifetime_seconds{mux=sm_b}")
+            .is_empty()
+        {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
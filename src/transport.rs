@@ -0,0 +1,200 @@
+use std::{error::Error, io, pin::Pin};
+
+use bytes::Bytes;
+use futures_util::{future, Sink as FutureSink, SinkExt, Stream as FutureStream, StreamExt};
+use tokio::io::{split, AsyncRead, AsyncWrite};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec, LengthDelimitedCodecError};
+use tracing::error;
+use tungstenite::Message;
+
+use crate::{
+    config::Config,
+    error::{DisconnectReason, FrameError},
+    frame::Frame,
+};
+
+/// Sink of frames the multiplexor writes to the transport.
+pub(crate) type FrameSink = Pin<Box<dyn FutureSink<Frame, Error = io::Error> + Send>>;
+/// Stream of frames the multiplexor reads from the transport, ending with
+/// the reason the transport went away.
+pub(crate) type FrameStream =
+    Pin<Box<dyn FutureStream<Item = Result<Frame, DisconnectReason>> + Send>>;
+/// Sink of encoded frames, or of what they are sealed in.
+pub(crate) type ByteSink = Pin<Box<dyn FutureSink<Vec<u8>, Error = io::Error> + Send>>;
+/// Stream of encoded frames, or of what they are sealed in.
+pub(crate) type ByteStream =
+    Pin<Box<dyn FutureStream<Item = Result<Vec<u8>, DisconnectReason>> + Send>>;
+
+/// What a message received from a message-oriented transport carries.
+#[derive(Debug)]
+pub enum MessageContent {
+    /// An encoded multiplexor frame.
+    Frame(Vec<u8>),
+    /// A control message handled by the transport itself, such as a
+    /// WebSocket ping.
+    Control,
+    /// The peer closed the transport.
+    Close {
+        /// The close code, if any.
+        code: Option<u16>,
+        /// The close reason, may be empty.
+        reason: String,
+    },
+}
+
+/// A message of a message-oriented transport, such as a WebSocket, that can
+/// carry a single encoded multiplexor frame.
+///
+/// Implement this to run a `WebSocketMultiplexor` over a `Sink` / `Stream`
+/// pair of your own message type.
+pub trait TransportMessage: Sized {
+    /// Wrap an encoded frame into a message.
+    fn from_frame(data: Vec<u8>) -> Self;
+
+    /// Classify a received message.
+    ///
+    /// # Errors
+    /// Returns an error if the message is not allowed on a multiplexor
+    /// transport.
+    fn into_content(self) -> Result<MessageContent, FrameError>;
+}
+
+impl TransportMessage for Message {
+    fn from_frame(data: Vec<u8>) -> Self {
+        Message::Binary(data)
+    }
+
+    fn into_content(self) -> Result<MessageContent, FrameError> {
+        match self {
+            Message::Binary(data) => Ok(MessageContent::Frame(data)),
+            // tungstenite answers pings itself
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => Ok(MessageContent::Control),
+            Message::Close(close) => Ok(match close {
+                Some(close) => MessageContent::Close {
+                    code: Some(close.code.into()),
+                    reason: close.reason.into_owned(),
+                },
+                None => MessageContent::Close {
+                    code: None,
+                    reason: String::new(),
+                },
+            }),
+            Message::Text(_) => Err(FrameError::UnexpectedText),
+        }
+    }
+}
+
+impl TransportMessage for Vec<u8> {
+    fn from_frame(data: Vec<u8>) -> Self {
+        data
+    }
+
+    fn into_content(self) -> Result<MessageContent, FrameError> {
+        Ok(MessageContent::Frame(self))
+    }
+}
+
+/// Adapt a message-oriented `Sink` / `Stream` pair to frames.
+pub(crate) fn from_messages<Sink, Stream, M, E>(
+    sink: Sink,
+    stream: Stream,
+) -> (FrameSink, FrameStream)
+where
+    M: TransportMessage + Send + 'static,
+    Sink: FutureSink<M> + Send + 'static,
+    Sink::Error: Error + Send + Sync + 'static,
+    Stream: FutureStream<Item = Result<M, E>> + Send + 'static,
+    E: Error + Send + Sync + 'static,
+{
+    let (sink, stream) = message_bytes(sink, stream);
+    frames(sink, stream)
+}
+
+/// Adapt a byte stream to frames, prefixing each frame with its length.
+pub(crate) fn from_io<T>(io: T, config: &Config) -> (FrameSink, FrameStream)
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (sink, stream) = io_bytes(io, config, config.max_frame_size);
+    frames(sink, stream)
+}
+
+/// Adapt a message-oriented `Sink` / `Stream` pair to encoded frames, one
+/// per message.
+pub(crate) fn message_bytes<Sink, Stream, M, E>(
+    sink: Sink,
+    stream: Stream,
+) -> (ByteSink, ByteStream)
+where
+    M: TransportMessage + Send + 'static,
+    Sink: FutureSink<M> + Send + 'static,
+    Sink::Error: Error + Send + Sync + 'static,
+    Stream: FutureStream<Item = Result<M, E>> + Send + 'static,
+    E: Error + Send + Sync + 'static,
+{
+    let sink = sink
+        .sink_map_err(io::Error::other)
+        .with(|data: Vec<u8>| future::ok(M::from_frame(data)));
+    let stream = stream.filter_map(|message| {
+        future::ready(match message {
+            Err(error) => {
+                error!("Error {:?} reading from transport", error);
+                Some(Err(DisconnectReason::Transport(io::ErrorKind::Other)))
+            }
+            Ok(message) => match message.into_content() {
+                Ok(MessageContent::Frame(data)) => Some(Ok(data)),
+                Ok(MessageContent::Control) => None,
+                Ok(MessageContent::Close { code, reason }) => {
+                    Some(Err(DisconnectReason::RemoteClosed { code, reason }))
+                }
+                Err(error) => Some(Err(DisconnectReason::Protocol(error))),
+            },
+        })
+    });
+    (Box::pin(sink), Box::pin(stream))
+}
+
+/// Adapt a byte stream to encoded frames of up to `max_len` bytes, each
+/// prefixed with its length.
+pub(crate) fn io_bytes<T>(io: T, config: &Config, max_len: usize) -> (ByteSink, ByteStream)
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let codec = LengthDelimitedCodec::builder()
+        .max_frame_length(max_len)
+        .new_codec();
+    let (read_half, write_half) = split(io);
+    let sink = FramedWrite::new(write_half, codec.clone())
+        .with(|data: Vec<u8>| future::ok::<Bytes, io::Error>(data.into()));
+    let max_frame_size = config.max_frame_size;
+    let stream = FramedRead::new(read_half, codec).map(move |data| match data {
+        Ok(data) => Ok(Vec::from(data)),
+        Err(error)
+            if error
+                .get_ref()
+                .is_some_and(|error| error.is::<LengthDelimitedCodecError>()) =>
+        {
+            Err(DisconnectReason::Protocol(FrameError::Oversize(
+                max_frame_size,
+            )))
+        }
+        Err(error) => Err(DisconnectReason::Transport(error.kind())),
+    });
+    (Box::pin(sink), Box::pin(stream))
+}
+
+/// Encode and decode the frames carried by encoded frame transports.
+fn frames(sink: ByteSink, stream: ByteStream) -> (FrameSink, FrameStream) {
+    let sink = sink.with(|frame: Frame| future::ok(Vec::from(frame)));
+    let stream = stream.map(|data| data.and_then(decode));
+    (Box::pin(sink), Box::pin(stream))
+}
+
+/// Decode a received frame.
+///
+/// Oversized frames are left to the reader, which resets their stream or
+/// disconnects if they belong to none. Only
+/// byte streams, which cannot skip them, disconnect on oversized frames.
+pub(crate) fn decode(data: Vec<u8>) -> Result<Frame, DisconnectReason> {
+    Frame::try_from(data).map_err(DisconnectReason::Protocol)
+}